@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+/// An ordered list of writes applied atomically by `KvsEngine::write`.
+///
+/// Either every operation of the batch is applied or none of them is. Like
+/// `KvsEngine::remove`, removing a key that does not exist is an error, and it
+/// fails the whole batch, while deleting a key that does not exist is skipped.
+/// A key set earlier in the same batch exists for the operations after it.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1".to_owned(), "value1".to_owned());
+/// batch.set("key2".to_owned(), "value2".to_owned());
+/// batch.remove("key1".to_owned());
+/// store.write(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single operation of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set {
+        /// The key.
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// The new value.
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key.
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Removes a key if it exists.
+    Delete {
+        /// The key.
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key to the batch.
+    ///
+    /// Keys and values are bytes, so strings and byte vectors both work.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    /// Adds removing a key to the batch.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+    }
+
+    /// Adds removing a key to the batch, unless the key does not exist when
+    /// the batch is applied.
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Delete { key: key.into() });
+    }
+
+    /// Returns the operations of the batch in order.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
@@ -1,18 +1,17 @@
-use std::io::Write;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::TcpStream;
+use std::process::exit;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 use kvs::validate_addr;
 use kvs::ClientCommand;
 use kvs::Result;
-use kvs::LOGGER;
-use serde::Deserialize;
-use serde::Serialize;
-use slog::info;
-use slog::Logger;
-use std::io::Read;
+use kvs::{read_frame, write_frame, KvsError, Request, Response, WriteBatch};
+
+/// 条件写入的条件不满足时的退出码，其他错误的退出码为 1
+const CONDITION_FAILED: i32 = 2;
 
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -31,6 +30,17 @@ fn main() -> Result<()> {
                         .help("The string value of the key")
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("NX")
+                        .long("nx")
+                        .help("Only set the key if it does not exist")
+                        .conflicts_with("XX"),
+                )
+                .arg(
+                    Arg::with_name("XX")
+                        .long("xx")
+                        .help("Only set the key if it already exists"),
+                )
                 .arg(
                     Arg::with_name("ADDR")
                         .long("addr")
@@ -63,97 +73,300 @@ fn main() -> Result<()> {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Replace the value of a key if it has the expected value")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("EXPECTED")
+                        .long("expected")
+                        .value_name("VALUE")
+                        .help("The expected value, the key must not exist if omitted")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("NEW")
+                        .long("new")
+                        .value_name("VALUE")
+                        .help("The new value, the key is removed if omitted")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help("Sets the IP address and port")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("Increment the integer value of a key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("BY")
+                        .long("by")
+                        .value_name("N")
+                        .help("The increment, 1 by default")
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help("Sets the IP address and port")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .about("Decrement the integer value of a key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("BY")
+                        .long("by")
+                        .value_name("N")
+                        .help("The decrement, 1 by default")
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help("Sets the IP address and port")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List key/value pairs in key order, one page at a time")
+                .arg(
+                    Arg::with_name("CURSOR")
+                        .long("cursor")
+                        .value_name("CURSOR")
+                        .help("The cursor printed by the previous page, 0 to start")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Only list keys that start with the prefix")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("COUNT")
+                        .long("count")
+                        .value_name("N")
+                        .help("The number of pairs in a page, 10 by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("REVERSE")
+                        .long("reverse")
+                        .help("List keys in reverse order"),
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help("Sets the IP address and port")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Apply several writes atomically")
+                .arg(
+                    Arg::with_name("OPS")
+                        .help("Writes in order, each `set KEY VALUE` or `rm KEY`")
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help("Sets the IP address and port")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    match matches.subcommand() {
+    let (request, matches) = match matches.subcommand() {
         ("set", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let value = matches.value_of("VALUE").unwrap();
-            let addr_value = matches.value_of("ADDR").unwrap_or("127.0.0.1:4000");
-            let (ip_addr, port) = match validate_addr(addr_value) {
-                Ok(result) => result,
-                Err(error_message) => panic!("{}", error_message),
+            let key = matches.value_of("KEY").unwrap().as_bytes().to_vec();
+            let value = matches.value_of("VALUE").unwrap().as_bytes().to_vec();
+            let command = if matches.is_present("NX") {
+                ClientCommand::SetIfAbsent { key, value }
+            } else if matches.is_present("XX") {
+                ClientCommand::SetIfPresent { key, value }
+            } else {
+                ClientCommand::Set { key, value }
             };
-
-            // 现在 ip_addr 和 port 可以在这里使用
-            // println!("IP Address: {}, Port: {}", ip_addr, port);
-            info!(LOGGER, "kvs-client listening in: {}", addr_value);
-            let _ = send_to_server(
-                ip_addr,
-                port,
-                ClientCommand::Set {
-                    key: key.to_string(),
-                    value: value.to_string(),
-                },
-            );
+            (Request::Command(command), matches)
         }
-        ("get", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let addr_value = matches.value_of("ADDR").unwrap_or("127.0.0.1:4000");
-            let (ip_addr, port) = match validate_addr(addr_value) {
-                Ok(result) => result,
-                Err(error_message) => panic!("{}", error_message),
+        ("get", Some(matches)) => (
+            Request::Command(ClientCommand::Get {
+                key: matches.value_of("KEY").unwrap().as_bytes().to_vec(),
+            }),
+            matches,
+        ),
+        ("rm", Some(matches)) => (
+            Request::Command(ClientCommand::Remove {
+                key: matches.value_of("KEY").unwrap().as_bytes().to_vec(),
+            }),
+            matches,
+        ),
+        ("cas", Some(matches)) => (
+            Request::Command(ClientCommand::CompareAndSwap {
+                key: matches.value_of("KEY").unwrap().as_bytes().to_vec(),
+                expected: matches.value_of("EXPECTED").map(|v| v.as_bytes().to_vec()),
+                new: matches.value_of("NEW").map(|v| v.as_bytes().to_vec()),
+            }),
+            matches,
+        ),
+        (name @ "incr", Some(matches)) | (name @ "decr", Some(matches)) => {
+            let by = matches.value_of("BY").unwrap_or("1");
+            let delta = match by.parse::<i64>() {
+                Ok(by) if name == "incr" => Some(by),
+                Ok(by) => by.checked_neg(),
+                Err(_) => None,
             };
-
-            // 现在 ip_addr 和 port 可以在这里使用
-            // println!("IP Address: {}, Port: {}", ip_addr, port);
-            info!(LOGGER, "kvs-client listening in: {}", addr_value);
-
-            let _ = send_to_server(
-                ip_addr,
-                port,
-                ClientCommand::Get {
-                    key: key.to_string(),
-                },
-            );
+            match delta {
+                Some(delta) => (
+                    Request::Command(ClientCommand::IncrBy {
+                        key: matches.value_of("KEY").unwrap().as_bytes().to_vec(),
+                        delta,
+                    }),
+                    matches,
+                ),
+                None => {
+                    eprintln!("Invalid {} amount: {}", name, by);
+                    exit(1);
+                }
+            }
         }
-        ("rm", Some(matches)) => {
-            let key = matches.value_of("KEY").unwrap();
-            let addr_value = matches.value_of("ADDR").unwrap_or("127.0.0.1:4000");
-            let (ip_addr, port) = match validate_addr(addr_value) {
-                Ok(result) => result,
-                Err(error_message) => panic!("{}", error_message),
+        ("scan", Some(matches)) => {
+            let count = matches.value_of("COUNT").unwrap_or("10");
+            let count = match count.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => {
+                    eprintln!("Invalid count: {}", count);
+                    exit(1);
+                }
             };
+            (
+                Request::Command(ClientCommand::Scan {
+                    cursor: matches.value_of("CURSOR").unwrap_or("0").to_string(),
+                    prefix: matches.value_of("PREFIX").map(|p| p.as_bytes().to_vec()),
+                    count,
+                    reverse: matches.is_present("REVERSE"),
+                }),
+                matches,
+            )
+        }
+        ("batch", Some(matches)) => match parse_batch(matches.values_of("OPS").unwrap()) {
+            Ok(batch) => (Request::Batch(batch), matches),
+            Err(error_message) => {
+                eprintln!("{}", error_message);
+                exit(1);
+            }
+        },
+        _ => unreachable!(),
+    };
 
-            // 现在 ip_addr 和 port 可以在这里使用
-            // println!("IP Address: {}, Port: {}", ip_addr, port);
-            info!(LOGGER, "kvs-client listening in: {}", addr_value);
+    // 条件写入的条件不满足时以单独的退出码退出
+    let conditional = matches!(
+        request,
+        Request::Command(
+            ClientCommand::SetIfAbsent { .. }
+                | ClientCommand::SetIfPresent { .. }
+                | ClientCommand::CompareAndSwap { .. }
+        )
+    );
 
-            let _ = send_to_server(
-                ip_addr,
-                port,
-                ClientCommand::Remove {
-                    key: key.to_string(),
-                },
-            );
+    let addr_value = matches.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+    let (ip_addr, port) = match validate_addr(addr_value) {
+        Ok(result) => result,
+        Err(error_message) => {
+            eprintln!("{}", error_message);
+            exit(1);
+        }
+    };
+
+    match send_to_server(ip_addr, port, request)? {
+        Response::Success => {}
+        Response::Value(Some(value)) => print_line(&[&value])?,
+        Response::Value(None) => println!("Key not found"),
+        Response::Integer(0) if conditional => {
+            eprintln!("Condition not met");
+            exit(CONDITION_FAILED);
+        }
+        Response::Integer(_) if conditional => {}
+        Response::Integer(i) => println!("{}", i),
+        // 第一行是下一页的游标，之后每行是一个以制表符分隔的键值对
+        Response::Scan { cursor, entries } => {
+            println!("{}", cursor);
+            for (key, value) in entries {
+                print_line(&[&key, b"\t", &value])?;
+            }
+        }
+        Response::Error(message) => {
+            eprintln!("{}", message);
+            exit(1);
         }
-        _ => unreachable!(),
     }
 
     Ok(())
 }
 
-pub enum ServerResponse {
-    Empty,
+/// 将几段字节原样输出为一行，value 不一定是 UTF-8
+fn print_line(parts: &[&[u8]]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for part in parts {
+        stdout.write_all(part)?;
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+/// 解析 batch 子命令的参数，例如 `set a 1 rm b`
+fn parse_batch<'a>(
+    mut ops: impl Iterator<Item = &'a str>,
+) -> std::result::Result<WriteBatch, String> {
+    let mut batch = WriteBatch::new();
+    while let Some(op) = ops.next() {
+        match (op, ops.next()) {
+            ("set", Some(key)) => match ops.next() {
+                Some(value) => batch.set(key.to_owned(), value.to_owned()),
+                None => return Err(format!("missing value for key '{}'", key)),
+            },
+            ("rm", Some(key)) => batch.remove(key.to_owned()),
+            ("set", None) | ("rm", None) => return Err(format!("missing key for '{}'", op)),
+            _ => return Err(format!("unknown batch operation '{}'", op)),
+        }
+    }
+    Ok(batch)
 }
 
-/// 向服务器发送消息
-fn send_to_server(ip_addr: IpAddr, port: u16, command: ClientCommand) -> Result<ServerResponse> {
+/// 向服务器发送请求，并返回服务器的执行结果
+fn send_to_server(ip_addr: IpAddr, port: u16, request: Request) -> Result<Response> {
     // 使用 ip_addr 和 port 构建 SocketAddr
     let socket_addr = SocketAddr::new(ip_addr, port);
-    let mut stream = TcpStream::connect(socket_addr)?;
+    let stream = TcpStream::connect(socket_addr)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
 
-    // 将 ClientCommand 对象序列化为 JSON 格式
-    let serialized_command = serde_json::to_string(&command)?;
-
-    stream.write_all(serialized_command.as_bytes())?;
+    write_frame(&mut writer, &request)?;
 
     // 读取服务器的响应
-    let mut buffer = Vec::new();
-    stream.read_to_end(&mut buffer)?;
-    // 将响应转换为字符串并返回
-    let response = String::from_utf8(buffer)?;
-    info!(LOGGER, "the response from server: {}", response);
-
-    Ok(ServerResponse::Empty)
+    match read_frame(&mut reader)? {
+        Some(response) => Ok(response),
+        None => Err(KvsError::Protocol(
+            "connection closed before a response was received".to_owned(),
+        )),
+    }
 }
@@ -1,10 +1,19 @@
 use clap::{App, AppSettings, Arg};
-use kvs::{validate_addr, Result, LOGGER};
-use slog::{error, info, Logger};
-use std::io::{Read, Write};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{
+    read_frame, validate_addr, write_frame, ClientCommand, KvStore, KvsEngine, KvsError, Request,
+    RespDecoder, RespValue, Response, Result, WriteBatch, FRAME_MAGIC, LOGGER,
+};
+use slog::{error, info};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::{Bound, Range};
+use std::process;
+use std::thread;
 use std::{
-    env, fmt,
-    net::{IpAddr, SocketAddr, TcpListener},
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
     str::FromStr,
 };
 
@@ -36,6 +45,13 @@ fn main() -> Result<()> {
                 .help("Specify the engine to use")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("THREADS")
+                .long("threads")
+                .value_name("N")
+                .help("Number of worker threads, defaults to the number of CPUs")
+                .takes_value(true),
+        )
         .get_matches();
 
     error!(LOGGER, "kvs-server {}:\n", env!("CARGO_PKG_VERSION"));
@@ -47,23 +63,53 @@ fn main() -> Result<()> {
             panic!("{}", error_message);
         }
     };
-    let engine_name = matches.value_of("ENGINE").unwrap_or("kvs");
-    let engine: Engine = match engine_name.parse() {
-        Ok(e) => e,
-        Err(e) => {
-            error!(LOGGER, "Invalid engine name: {}", engine_name);
-            panic!("Invalid engine name: {}", engine_name);
+    // 未指定 --engine 时沿用数据目录中记录或已有数据所属的引擎，都没有时默认使用 kvs
+    let recorded_engine = recorded_engine()?;
+    let engine = match matches.value_of("ENGINE") {
+        Some(engine_name) => match engine_name.parse() {
+            Ok(e) => e,
+            Err(_) => {
+                error!(LOGGER, "Invalid engine name: {}", engine_name);
+                panic!("Invalid engine name: {}", engine_name);
+            }
+        },
+        None => recorded_engine.unwrap_or(Engine::Kvs),
+    };
+    if let Some(recorded) = recorded_engine {
+        if recorded != engine {
+            let err = KvsError::WrongEngine {
+                recorded: recorded.to_string(),
+                requested: engine.to_string(),
+            };
+            error!(LOGGER, "{}", err);
+            return Err(err);
         }
+    }
+
+    let threads = match matches.value_of("THREADS") {
+        Some(threads) => match threads.parse::<u32>() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                error!(LOGGER, "Invalid number of threads: {}", threads);
+                panic!("Invalid number of threads: {}", threads);
+            }
+        },
+        None => thread::available_parallelism().map_or(1, |n| n.get() as u32),
     };
 
     // 现在 ip_addr 和 port 可以在这里使用
     // eprintln!("IP Address: {}, Port: {}", ip_addr, port);
+    error!(LOGGER, "Storage engine: {}", engine);
+    error!(LOGGER, "Worker threads: {}", threads);
     error!(LOGGER, "Listening in: {}", addr_value);
 
-    start_service(ip_addr, port, engine)
+    start_service(ip_addr, port, engine, threads)
 }
 
-#[derive(Debug, PartialEq)]
+/// 记录数据目录所用引擎的标记文件
+const ENGINE_FILE: &str = "engine";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Engine {
     Kvs,
     Sled,
@@ -94,27 +140,570 @@ impl fmt::Display for Engine {
     }
 }
 
+/// 读取当前目录中记录的引擎，没有记录或记录无效时根据已有的数据文件推断，
+/// 首次启动时返回 None
+fn recorded_engine() -> Result<Option<Engine>> {
+    let engine_file = env::current_dir()?.join(ENGINE_FILE);
+    if !engine_file.exists() {
+        return existing_engine();
+    }
+
+    let content = fs::read_to_string(engine_file).map_err(|e| e.to_string());
+    match content.and_then(|content| content.trim().parse()) {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            error!(LOGGER, "Ignoring the invalid engine file: {}", e);
+            existing_engine()
+        }
+    }
+}
+
+/// 根据当前目录中已有的数据文件推断使用的引擎，目录中没有数据时返回 None
+///
+/// kvs 的数据是 `<gen>.log` 文件，sled 的数据包括 `conf` 和 `db` 文件。
+fn existing_engine() -> Result<Option<Engine>> {
+    let mut engine = None;
+    for entry in fs::read_dir(env::current_dir()?)? {
+        let path = entry?.path();
+        let is_log = path.extension() == Some("log".as_ref())
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| stem.parse::<u64>().is_ok());
+        if is_log {
+            return Ok(Some(Engine::Kvs));
+        }
+        if path.ends_with("conf") || path.ends_with("db") {
+            engine = Some(Engine::Sled);
+        }
+    }
+    Ok(engine)
+}
+
+/// 记录当前目录所用的引擎，之后的启动都必须使用同一个引擎
+fn record_engine(engine: Engine) -> Result<()> {
+    fs::write(env::current_dir()?.join(ENGINE_FILE), engine.to_string())?;
+    Ok(())
+}
+
 /// 启动服务
-fn start_service(ip_addr: IpAddr, port: u16, engine: Engine) -> Result<()> {
+///
+/// 存储引擎打开成功之后才记录引擎，避免目录被一个没有启动过的引擎占用。
+fn start_service(ip_addr: IpAddr, port: u16, engine: Engine, threads: u32) -> Result<()> {
+    let pool = SharedQueueThreadPool::new(threads)?;
+    match engine {
+        Engine::Kvs => {
+            let store = KvStore::open(env::current_dir()?)?;
+            record_engine(engine)?;
+            serve(ip_addr, port, store, pool)
+        }
+        #[cfg(feature = "sled")]
+        Engine::Sled => {
+            let db = SledKvsEngine::open(env::current_dir()?)?;
+            record_engine(engine)?;
+            serve(ip_addr, port, db, pool)
+        }
+        #[cfg(not(feature = "sled"))]
+        Engine::Sled => {
+            error!(LOGGER, "kvs-server was built without the sled feature");
+            process::exit(1);
+        }
+    }
+}
+
+/// 监听端口，在线程池中使用给定的存储引擎处理所有连接
+fn serve<E: KvsEngine, P: ThreadPool>(
+    ip_addr: IpAddr,
+    port: u16,
+    engine: E,
+    pool: P,
+) -> Result<()> {
     // 使用 ip_addr 和 port 构建 SocketAddr
     let socket_addr = SocketAddr::new(ip_addr, port);
     let listener = TcpListener::bind(socket_addr)?;
 
     for stream in listener.incoming() {
-        let mut stream = stream?;
+        match stream {
+            Ok(stream) => {
+                // 每个连接持有引擎的一个克隆，克隆之间共享同一份数据
+                let engine = engine.clone();
+                pool.spawn(move || {
+                    if let Err(e) = handle_connection(&engine, stream) {
+                        error!(LOGGER, "Error on serving client: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!(LOGGER, "Connection failed: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// 根据连接的第一个字节判断客户端使用的协议，并交给对应的处理函数
+fn handle_connection<E: KvsEngine>(engine: &E, stream: TcpStream) -> Result<()> {
+    let mut first_byte = [0; 1];
+    if stream.peek(&mut first_byte)? == 0 {
+        return Ok(());
+    }
+    if first_byte[0] == FRAME_MAGIC {
+        handle_frames(engine, stream)
+    } else {
+        handle_resp(engine, stream)
+    }
+}
 
-        // 读取客户端发送的请求数据
-        let mut buffer = [0; 512];
-        stream.read(&mut buffer)?;
-        info!(
-            LOGGER,
-            "Received from kvs-client: {}",
-            String::from_utf8_lossy(&buffer[..])
-        );
+/// 持续读取 kvs-client 发送的请求，执行后将结果写回客户端，直到客户端关闭连接
+fn handle_frames<E: KvsEngine>(engine: &E, stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
 
-        // 处理请求并发送响应
-        stream.write(b"Response from kvs-server")?;
+    while let Some(request) = read_frame::<_, Request>(&mut reader)? {
+        info!(LOGGER, "Received from {}: {:?}", peer_addr, request);
+        let response = match request {
+            Request::Command(command) => match execute(engine, command) {
+                Ok(response) => response,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Batch(batch) => match engine.write(batch) {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error(e.to_string()),
+            },
+        };
+        write_frame(&mut writer, &response)?;
+        info!(LOGGER, "Response sent to {}: {:?}", peer_addr, response);
     }
 
     Ok(())
 }
+
+/// 持续读取 Redis 客户端发送的 RESP 请求，执行后将结果写回客户端，直到客户端关闭连接
+fn handle_resp<E: KvsEngine>(engine: &E, mut stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut decoder = RespDecoder::new();
+    let mut chunk = [0; 4096];
+    // 连接默认使用 RESP2，客户端可以通过 HELLO 指令切换
+    let mut protocol = 2;
+    // MULTI 之后、EXEC 或 DISCARD 之前缓存的事务
+    let mut transaction = None;
+
+    loop {
+        // 客户端可能一次发送多条指令，先处理完缓冲区中所有完整的请求
+        loop {
+            let value = match decoder.decode_request() {
+                Ok(Some(value)) => value,
+                Ok(None) => break,
+                Err(KvsError::Protocol(message)) => {
+                    // 无法继续解析后续的数据，回复错误后关闭连接
+                    let reply = RespValue::Error(format!("ERR Protocol error: {}", message));
+                    reply.encode(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            info!(LOGGER, "Received from {}: {:?}", peer_addr, value);
+
+            let reply = match ClientCommand::from_resp(value) {
+                command @ Ok(
+                    ClientCommand::Multi | ClientCommand::Exec | ClientCommand::Discard,
+                ) => transact(engine, command, &mut transaction),
+                command if transaction.is_some() => transact(engine, command, &mut transaction),
+                Ok(ClientCommand::Hello { protover }) => hello(&mut protocol, protover),
+                Ok(command) => execute_resp(engine, command, protocol),
+                Err(e) => command_error(e),
+            };
+            let reply = if protocol == 2 {
+                reply.into_resp2()
+            } else {
+                reply
+            };
+            reply.encode(&mut writer)?;
+            info!(LOGGER, "Response sent to {}: {:?}", peer_addr, reply);
+        }
+        writer.flush()?;
+
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(());
+        }
+        decoder.feed(&chunk[..len]);
+    }
+}
+
+/// 在存储引擎上执行一条指令
+fn execute<E: KvsEngine>(engine: &E, command: ClientCommand) -> Result<Response> {
+    match command {
+        ClientCommand::Set { key, value } => {
+            engine.set_bytes(key, value)?;
+            Ok(Response::Success)
+        }
+        ClientCommand::Get { key } => Ok(Response::Value(engine.get_bytes(key)?)),
+        ClientCommand::Remove { key } => {
+            engine.remove_bytes(key)?;
+            Ok(Response::Success)
+        }
+        ClientCommand::Delete { keys } => Ok(Response::Integer(delete(engine, keys)?)),
+        ClientCommand::Exists { keys } => Ok(Response::Integer(exists(engine, keys)?)),
+        ClientCommand::PING => Ok(Response::Value(Some(b"PONG".to_vec()))),
+        ClientCommand::Hello { .. } => Ok(Response::Error(
+            "HELLO is only supported over RESP".to_owned(),
+        )),
+        ClientCommand::Info => Ok(Response::Value(Some(format_info(2).into_bytes()))),
+        ClientCommand::Multi | ClientCommand::Exec | ClientCommand::Discard => Ok(Response::Error(
+            "MULTI is only supported over RESP, send a batch instead".to_owned(),
+        )),
+        ClientCommand::CompareAndSwap { key, expected, new } => Ok(Response::Integer(
+            engine.compare_and_swap_bytes(key, expected, new)? as i64,
+        )),
+        ClientCommand::SetIfAbsent { key, value } | ClientCommand::SetNx { key, value } => Ok(
+            Response::Integer(engine.set_if_absent_bytes(key, value)? as i64),
+        ),
+        ClientCommand::SetIfPresent { key, value } => Ok(Response::Integer(
+            engine.set_if_present_bytes(key, value)? as i64,
+        )),
+        ClientCommand::IncrBy { key, delta } => {
+            Ok(Response::Integer(engine.incr_by_bytes(key, delta)?))
+        }
+        ClientCommand::Scan {
+            cursor,
+            prefix,
+            count,
+            reverse,
+        } => {
+            let (cursor, entries) = scan_page(engine, &cursor, prefix, count, reverse)?;
+            Ok(Response::Scan { cursor, entries })
+        }
+    }
+}
+
+/// 在存储引擎上执行一条指令，并按照 Redis 的语义构造回复
+///
+/// 回复使用 RESP3 的类型构造，发送给 RESP2 客户端之前需要调用 `into_resp2`。
+fn execute_resp<E: KvsEngine>(engine: &E, command: ClientCommand, protocol: i64) -> RespValue {
+    let result = match command {
+        ClientCommand::Set { key, value } => engine
+            .set_bytes(key, value)
+            .map(|_| RespValue::SimpleStrings("OK".to_owned())),
+        ClientCommand::Get { key } => engine.get_bytes(key).map(|value| match value {
+            Some(value) => RespValue::BulkStrings(Some(value)),
+            None => RespValue::Null,
+        }),
+        ClientCommand::Remove { key } => match engine.remove_bytes(key) {
+            Ok(()) => Ok(RespValue::Integer(1)),
+            Err(KvsError::KeyNotFound) => Ok(RespValue::Integer(0)),
+            Err(e) => Err(e),
+        },
+        ClientCommand::Delete { keys } => delete(engine, keys).map(RespValue::Integer),
+        ClientCommand::Exists { keys } => exists(engine, keys).map(RespValue::Integer),
+        ClientCommand::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap_bytes(key, expected, new)
+            .map(|swapped| RespValue::Integer(swapped as i64)),
+        ClientCommand::SetNx { key, value } => engine
+            .set_if_absent_bytes(key, value)
+            .map(|set| RespValue::Integer(set as i64)),
+        // SET NX|XX 在条件不满足时回复 nil
+        ClientCommand::SetIfAbsent { key, value } => {
+            engine.set_if_absent_bytes(key, value).map(set_reply)
+        }
+        ClientCommand::SetIfPresent { key, value } => {
+            engine.set_if_present_bytes(key, value).map(set_reply)
+        }
+        ClientCommand::IncrBy { key, delta } => {
+            engine.incr_by_bytes(key, delta).map(RespValue::Integer)
+        }
+        // 与 Redis 相同，SCAN 只返回 key
+        ClientCommand::Scan {
+            cursor,
+            prefix,
+            count,
+            reverse,
+        } => scan_page(engine, &cursor, prefix, count, reverse).map(|(cursor, entries)| {
+            RespValue::Array(vec![
+                bulk(&cursor),
+                RespValue::Array(
+                    entries
+                        .into_iter()
+                        .map(|(key, _)| RespValue::BulkStrings(Some(key)))
+                        .collect(),
+                ),
+            ])
+        }),
+        ClientCommand::PING => Ok(RespValue::SimpleStrings("PONG".to_owned())),
+        // HELLO 和事务相关的指令会修改连接的状态，由 handle_resp 直接处理
+        ClientCommand::Hello { .. }
+        | ClientCommand::Multi
+        | ClientCommand::Exec
+        | ClientCommand::Discard => unreachable!(),
+        ClientCommand::Info if protocol == 3 => Ok(RespValue::Map(
+            server_info(protocol)
+                .into_iter()
+                .map(|(field, value)| (bulk(field), bulk(&value)))
+                .collect(),
+        )),
+        ClientCommand::Info => Ok(bulk(&format_info(protocol))),
+    };
+    result.unwrap_or_else(|e| RespValue::Error(format!("ERR {}", e)))
+}
+
+/// 一个键值对
+type Pair = (Vec<u8>, Vec<u8>);
+
+/// 扫描开始和结束时的游标
+const SCAN_DONE: &str = "0";
+
+/// 从游标处开始扫描最多 count 个键值对，返回下一页的游标和这一页的键值对
+///
+/// key 是有序的，所以游标只需要记录这一页的最后一个 key。逆序扫描时，下一页
+/// 从这个 key 之前继续。
+fn scan_page<E: KvsEngine>(
+    engine: &E,
+    cursor: &str,
+    prefix: Option<Vec<u8>>,
+    count: usize,
+    reverse: bool,
+) -> Result<(String, Vec<Pair>)> {
+    let after = decode_cursor(cursor)?;
+    let entries = if reverse {
+        let end = match (after, prefix.as_deref().and_then(prefix_end)) {
+            (Some(after), Some(end)) if after >= end => Bound::Excluded(end),
+            (Some(after), _) => Bound::Excluded(after),
+            (None, Some(end)) => Bound::Excluded(end),
+            (None, None) => Bound::Unbounded,
+        };
+        engine.scan_rev_bytes((Bound::Unbounded, end), usize::MAX)
+    } else {
+        let start = match (after, &prefix) {
+            (Some(after), Some(prefix)) if after < *prefix => Bound::Included(prefix.clone()),
+            (Some(after), _) => Bound::Excluded(after),
+            (None, Some(prefix)) => Bound::Included(prefix.clone()),
+            (None, None) => Bound::Unbounded,
+        };
+        engine.scan_bytes((start, Bound::Unbounded), usize::MAX)
+    };
+    let prefix = prefix.unwrap_or_default();
+    let entries = entries
+        .take_while(|entry| match entry {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })
+        .take(count)
+        .collect::<Result<Vec<_>>>()?;
+    let cursor = match entries.last() {
+        Some((key, _)) if entries.len() == count => encode_cursor(key),
+        _ => SCAN_DONE.to_owned(),
+    };
+    Ok((cursor, entries))
+}
+
+/// 返回大于所有以 prefix 开头的 key 的最小 key，不存在时返回 None
+///
+/// 去掉末尾的 0xff 之后递增最后一个字节即可。
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// 将一页的最后一个 key 编码为游标，使用十六进制避免与 "0" 冲突
+fn encode_cursor(key: &[u8]) -> String {
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("k{}", hex)
+}
+
+/// 解析游标，返回上一页的最后一个 key
+fn decode_cursor(cursor: &str) -> Result<Option<Vec<u8>>> {
+    if cursor == SCAN_DONE {
+        return Ok(None);
+    }
+    let invalid = || KvsError::Protocol("invalid cursor".to_owned());
+    let hex = cursor.strip_prefix('k').ok_or_else(invalid)?;
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .map(Some)
+        .ok_or_else(invalid)
+}
+
+/// 原子地删除多个 key，返回实际删除的个数
+fn delete<E: KvsEngine>(engine: &E, keys: Vec<Vec<u8>>) -> Result<i64> {
+    let mut batch = WriteBatch::new();
+    for key in keys {
+        batch.delete(key);
+    }
+    let applied = engine.write(batch)?;
+    Ok(applied.into_iter().filter(|&removed| removed).count() as i64)
+}
+
+/// 返回多个 key 中存在的个数
+fn exists<E: KvsEngine>(engine: &E, keys: Vec<Vec<u8>>) -> Result<i64> {
+    let mut count = 0;
+    for key in keys {
+        if engine.get_bytes(key)?.is_some() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// 条件写入的回复，写入时为 OK，否则为 nil
+fn set_reply(set: bool) -> RespValue {
+    if set {
+        RespValue::SimpleStrings("OK".to_owned())
+    } else {
+        RespValue::Null
+    }
+}
+
+/// MULTI 之后缓存的写指令，EXEC 时作为一个 WriteBatch 原子地写入
+#[derive(Default)]
+struct Transaction {
+    batch: WriteBatch,
+    // EXEC 成功时每条指令的回复
+    replies: Vec<QueuedReply>,
+    // 缓存指令时出现过错误，EXEC 会放弃整个事务
+    aborted: bool,
+}
+
+/// 事务中一条指令的回复
+enum QueuedReply {
+    Ok,
+    // DEL 删除的 key 的个数，由 batch 中这些操作是否生效决定，写入之后才能确定
+    Removed(Range<usize>),
+}
+
+/// 处理 MULTI、EXEC、DISCARD 以及事务中的指令
+///
+/// 事务中只支持 SET 和 DEL，其他指令会使整个事务在 EXEC 时被放弃。
+fn transact<E: KvsEngine>(
+    engine: &E,
+    command: Result<ClientCommand>,
+    transaction: &mut Option<Transaction>,
+) -> RespValue {
+    let ok = || RespValue::SimpleStrings("OK".to_owned());
+    let queued = || RespValue::SimpleStrings("QUEUED".to_owned());
+    match command {
+        Ok(ClientCommand::Multi) if transaction.is_some() => {
+            RespValue::Error("ERR MULTI calls can not be nested".to_owned())
+        }
+        Ok(ClientCommand::Multi) => {
+            *transaction = Some(Transaction::default());
+            ok()
+        }
+        Ok(ClientCommand::Discard) => match transaction.take() {
+            Some(_) => ok(),
+            None => RespValue::Error("ERR DISCARD without MULTI".to_owned()),
+        },
+        Ok(ClientCommand::Exec) => match transaction.take() {
+            None => RespValue::Error("ERR EXEC without MULTI".to_owned()),
+            Some(tx) if tx.aborted => RespValue::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_owned(),
+            ),
+            // 批量写入失败时没有任何指令生效，因此回复一个错误而不是每条指令的结果
+            Some(tx) => match engine.write(tx.batch) {
+                Ok(applied) => RespValue::Array(
+                    tx.replies
+                        .into_iter()
+                        .map(|reply| match reply {
+                            QueuedReply::Ok => ok(),
+                            QueuedReply::Removed(ops) => RespValue::Integer(
+                                applied[ops].iter().filter(|&&removed| removed).count() as i64,
+                            ),
+                        })
+                        .collect(),
+                ),
+                Err(e) => RespValue::Error(format!("ERR {}", e)),
+            },
+        },
+        command => {
+            let tx = transaction.as_mut().expect("not in a transaction");
+            match command {
+                Ok(ClientCommand::Set { key, value }) => {
+                    tx.batch.set(key, value);
+                    tx.replies.push(QueuedReply::Ok);
+                    queued()
+                }
+                // 与事务外的 DEL 相同，删除不存在的 key 不是错误
+                Ok(ClientCommand::Delete { keys }) => {
+                    let start = tx.batch.len();
+                    for key in keys {
+                        tx.batch.delete(key);
+                    }
+                    tx.replies.push(QueuedReply::Removed(start..tx.batch.len()));
+                    queued()
+                }
+                Ok(_) => {
+                    tx.aborted = true;
+                    RespValue::Error("ERR only SET and DEL are supported inside MULTI".to_owned())
+                }
+                Err(e) => {
+                    tx.aborted = true;
+                    command_error(e)
+                }
+            }
+        }
+    }
+}
+
+/// 将解析指令时的错误转换为 RESP 错误回复
+fn command_error(e: KvsError) -> RespValue {
+    match e {
+        KvsError::Protocol(message) => RespValue::Error(format!("ERR {}", message)),
+        e => RespValue::Error(format!("ERR {}", e)),
+    }
+}
+
+/// 处理 HELLO 指令，切换连接使用的协议版本并返回服务器信息
+fn hello(protocol: &mut i64, protover: Option<i64>) -> RespValue {
+    match protover {
+        Some(version) if version != 2 && version != 3 => {
+            return RespValue::Error("NOPROTO unsupported protocol version".to_owned())
+        }
+        Some(version) => *protocol = version,
+        None => {}
+    }
+    RespValue::Map(vec![
+        (bulk("server"), bulk("kvs")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), RespValue::Integer(*protocol)),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk("master")),
+        (bulk("modules"), RespValue::Array(vec![])),
+    ])
+}
+
+/// INFO 指令返回的服务器信息
+fn server_info(protocol: i64) -> Vec<(&'static str, String)> {
+    vec![
+        ("kvs_version", env!("CARGO_PKG_VERSION").to_owned()),
+        ("process_id", process::id().to_string()),
+        ("resp_protocol", protocol.to_string()),
+    ]
+}
+
+/// 按照 Redis 的文本格式输出 INFO 信息
+fn format_info(protocol: i64) -> String {
+    let mut info = "# Server\r\n".to_owned();
+    for (field, value) in server_info(protocol) {
+        info.push_str(&format!("{}:{}\r\n", field, value));
+    }
+    info
+}
+
+fn bulk(s: &str) -> RespValue {
+    RespValue::BulkStrings(Some(s.as_bytes().to_vec()))
+}
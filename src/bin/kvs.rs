@@ -2,6 +2,7 @@ use clap::{App, AppSettings, Arg, SubCommand};
 use kvs::KvsEngine;
 use kvs::{KvStore, KvsError, Result};
 use std::env::current_dir;
+use std::io::{self, Write};
 use std::process::exit;
 
 fn main() -> Result<()> {
@@ -39,15 +40,17 @@ fn main() -> Result<()> {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             store.set(key.to_string(), value.to_string())?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
-            if let Some(value) = store.get(key.to_string())? {
-                println!("{}", value);
+            let store = KvStore::open(current_dir()?)?;
+            // 值不一定是 UTF-8，原样输出
+            if let Some(mut value) = store.get_bytes(key.as_bytes().to_vec())? {
+                value.push(b'\n');
+                io::stdout().write_all(&value)?;
             } else {
                 println!("Key not found");
             }
@@ -55,7 +58,7 @@ fn main() -> Result<()> {
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             match store.remove(key.to_string()) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
@@ -1,12 +1,300 @@
+use crate::resp::RespValue;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+
 /// Redis 支持的所有指令
-#[derive(Debug)]
+///
+/// key 和 value 都是任意的字节
+#[derive(Serialize, Deserialize, Debug)]
 pub enum ClientCommand {
     // 设置一个key 的值
-    Set { key: String, value: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
     // 获取一个 key 的值
-    Get { key: String },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
     // 移除一个 key 的值
-    Remove { key: String },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    // 删除多个 key，返回删除的个数，不存在的 key 被忽略
+    Delete {
+        keys: Vec<Vec<u8>>,
+    },
+    // 返回多个 key 中存在的个数，重复的 key 计算多次
+    Exists {
+        keys: Vec<Vec<u8>>,
+    },
     // 测试命令
     PING,
+    // 协商连接使用的 RESP 协议版本
+    Hello {
+        protover: Option<i64>,
+    },
+    // 查看服务器信息
+    Info,
+    // 开始一个事务，之后的写指令会被缓存到 EXEC 时一起执行
+    Multi,
+    // 原子地执行事务中缓存的所有写指令
+    Exec,
+    // 丢弃事务中缓存的所有写指令
+    Discard,
+    // 当 key 的值等于 expected 时替换为 new，None 表示 key 不存在
+    CompareAndSwap {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    // 仅当 key 不存在时设置它的值
+    SetIfAbsent {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    // SETNX，与 SET NX 相同，但使用整数回复
+    SetNx {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    // 仅当 key 存在时设置它的值
+    SetIfPresent {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    // 将 key 的整数值加上 delta，INCR、DECR、INCRBY 和 DECRBY 都转换为这条指令
+    IncrBy {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        delta: i64,
+    },
+    // 从游标处开始按 key 的顺序返回最多 count 个键值对，游标 "0" 表示从头开始，
+    // reverse 时按 key 的逆序返回
+    Scan {
+        cursor: String,
+        #[serde(with = "serde_bytes")]
+        prefix: Option<Vec<u8>>,
+        count: usize,
+        reverse: bool,
+    },
+}
+
+impl ClientCommand {
+    /// 将 RESP 数组形式的请求解析为指令，例如 `*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n`
+    pub fn from_resp(value: RespValue) -> Result<ClientCommand> {
+        let items = match value {
+            RespValue::Array(items) if !items.is_empty() => items,
+            _ => {
+                return Err(KvsError::Protocol(
+                    "expected a non-empty array of bulk strings".to_owned(),
+                ))
+            }
+        };
+        let mut args = Vec::with_capacity(items.len());
+        for item in items {
+            match item {
+                RespValue::BulkStrings(Some(data)) => args.push(data),
+                _ => {
+                    return Err(KvsError::Protocol(
+                        "expected a non-empty array of bulk strings".to_owned(),
+                    ))
+                }
+            }
+        }
+
+        let name = String::from_utf8_lossy(&args.remove(0)).to_lowercase();
+        let mut args = args.into_iter();
+        let command = match (name.as_str(), args.len()) {
+            ("set", 2) => ClientCommand::Set {
+                key: args.next().unwrap(),
+                value: args.next().unwrap(),
+            },
+            ("set", 3) => parse_set_condition(args)?,
+            ("setnx", 2) => ClientCommand::SetNx {
+                key: args.next().unwrap(),
+                value: args.next().unwrap(),
+            },
+            ("incr", 1) => ClientCommand::IncrBy {
+                key: args.next().unwrap(),
+                delta: 1,
+            },
+            ("decr", 1) => ClientCommand::IncrBy {
+                key: args.next().unwrap(),
+                delta: -1,
+            },
+            ("incrby", 2) => ClientCommand::IncrBy {
+                key: args.next().unwrap(),
+                delta: parse_integer(&args.next().unwrap())?,
+            },
+            ("decrby", 2) => ClientCommand::IncrBy {
+                key: args.next().unwrap(),
+                delta: parse_integer(&args.next().unwrap())?
+                    .checked_neg()
+                    .ok_or_else(|| KvsError::Protocol("decrement would overflow".to_owned()))?,
+            },
+            ("get", 1) => ClientCommand::Get {
+                key: args.next().unwrap(),
+            },
+            ("del", n) if n > 0 => ClientCommand::Delete {
+                keys: args.collect(),
+            },
+            ("exists", n) if n > 0 => ClientCommand::Exists {
+                keys: args.collect(),
+            },
+            ("ping", 0) => ClientCommand::PING,
+            ("hello", _) => parse_hello(args)?,
+            ("scan", n) if n > 0 => parse_scan(args)?,
+            ("info", 0) | ("info", 1) => ClientCommand::Info,
+            ("multi", 0) => ClientCommand::Multi,
+            ("exec", 0) => ClientCommand::Exec,
+            ("discard", 0) => ClientCommand::Discard,
+            ("set", _)
+            | ("setnx", _)
+            | ("incr", _)
+            | ("decr", _)
+            | ("incrby", _)
+            | ("decrby", _)
+            | ("get", _)
+            | ("del", _)
+            | ("exists", _)
+            | ("ping", _)
+            | ("info", _)
+            | ("scan", _)
+            | ("multi", _)
+            | ("exec", _)
+            | ("discard", _) => {
+                return Err(KvsError::Protocol(format!(
+                    "wrong number of arguments for '{}' command",
+                    name
+                )))
+            }
+            _ => {
+                return Err(KvsError::Protocol(format!(
+                    "unknown command '{}'",
+                    printable(&name)
+                )))
+            }
+        };
+        Ok(command)
+    }
+}
+
+/// 解析指令中的整数参数
+fn parse_integer(arg: &[u8]) -> Result<i64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("value is not an integer or out of range".to_owned()))
+}
+
+/// 解析 `SET key value NX|XX`
+fn parse_set_condition(mut args: impl Iterator<Item = Vec<u8>>) -> Result<ClientCommand> {
+    let key = args.next().unwrap();
+    let value = args.next().unwrap();
+    match option_name(args.next().unwrap()).as_str() {
+        "nx" => Ok(ClientCommand::SetIfAbsent { key, value }),
+        "xx" => Ok(ClientCommand::SetIfPresent { key, value }),
+        _ => Err(KvsError::Protocol("syntax error".to_owned())),
+    }
+}
+
+/// 解析 `SCAN cursor [MATCH pattern] [COUNT count] [REV]`
+///
+/// 只支持 `user:*` 这样的前缀模式。REV 不是 Redis 的选项，表示按 key 的逆序扫描。
+fn parse_scan(mut args: impl Iterator<Item = Vec<u8>>) -> Result<ClientCommand> {
+    let cursor = String::from_utf8(args.next().unwrap())
+        .map_err(|_| KvsError::Protocol("invalid cursor".to_owned()))?;
+    let mut prefix = None;
+    let mut count = 10;
+    let mut reverse = false;
+    while let Some(option) = args.next() {
+        let option = option_name(option);
+        if option == "rev" {
+            reverse = true;
+            continue;
+        }
+        match (option.as_str(), args.next()) {
+            ("match", Some(pattern)) => {
+                let glob = |c: &u8| matches!(c, b'*' | b'?' | b'[' | b'\\');
+                prefix = match pattern.strip_suffix(b"*") {
+                    Some(p) if !p.iter().any(glob) => Some(p.to_vec()).filter(|p| !p.is_empty()),
+                    _ => {
+                        return Err(KvsError::Protocol(
+                            "only prefix patterns such as 'user:*' are supported".to_owned(),
+                        ))
+                    }
+                };
+            }
+            ("count", Some(n)) => {
+                count = match parse_integer(&n)? {
+                    n if n > 0 => n as usize,
+                    _ => return Err(KvsError::Protocol("syntax error".to_owned())),
+                }
+            }
+            _ => return Err(KvsError::Protocol("syntax error".to_owned())),
+        }
+    }
+    Ok(ClientCommand::Scan {
+        cursor,
+        prefix,
+        count,
+        reverse,
+    })
+}
+
+/// 解析 `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+fn parse_hello(mut args: impl Iterator<Item = Vec<u8>>) -> Result<ClientCommand> {
+    let protover = match args.next() {
+        Some(arg) => Some(parse_integer(&arg).map_err(|_| {
+            KvsError::Protocol("Protocol version is not an integer or out of range".to_owned())
+        })?),
+        None => None,
+    };
+    while let Some(option) = args.next() {
+        let option = option_name(option);
+        match option.as_str() {
+            // 连接名称只用于展示，直接忽略
+            "setname" if args.next().is_some() => {}
+            "auth" => {
+                return Err(KvsError::Protocol(
+                    "AUTH is not supported by kvs-server".to_owned(),
+                ))
+            }
+            _ => {
+                return Err(KvsError::Protocol(format!(
+                    "Syntax error in HELLO option '{}'",
+                    printable(&option)
+                )))
+            }
+        }
+    }
+    Ok(ClientCommand::Hello { protover })
+}
+
+/// 将指令的选项转换为小写的字符串，例如 `NX`
+fn option_name(option: Vec<u8>) -> String {
+    String::from_utf8_lossy(&option).to_lowercase()
+}
+
+/// 错误回复中回显的客户端输入，与 Redis 相同，最多保留 128 个字符，
+/// 并将换行替换为空格，避免在回复中插入额外的 RESP 数据
+fn printable(arg: &str) -> String {
+    arg.chars()
+        .take(128)
+        .map(|c| if c == '\r' || c == '\n' { ' ' } else { c })
+        .collect()
 }
@@ -1,14 +1,176 @@
-use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use crate::{Result, WriteBatch};
+
+/// iterator over key/value pairs in key order
+pub type Scan<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// iterator over binary key/value pairs in key order
+pub type ScanBytes<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
 
 ///
 /// kvs engine definition
-pub trait KvsEngine {
+///
+/// An engine is a handle that can be cloned and sent to other threads; all
+/// clones operate on the same underlying store.
+///
+/// Keys and values are arbitrary bytes. The methods taking and returning
+/// `String` are a convenience layer over the `_bytes` methods, and fail with
+/// `KvsError::EncodeError` when a stored key or value is not UTF-8.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// set key
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// get key
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// remove key
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// apply all writes of the batch, or none of them; returns whether each
+    /// operation was applied, which is false only for a delete of a missing key
+    fn write(&self, batch: WriteBatch) -> Result<Vec<bool>>;
+
+    /// replace the value of key with `new` if it is `expected`, `None` meaning absent;
+    /// returns whether it was replaced
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// set key if it does not exist; returns whether it was set
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// set key if it exists; returns whether it was set
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// add delta to the integer value of key, a missing key counting as 0;
+    /// returns the new value
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    /// set key to a value that expires after ttl
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// make an existing key expire after ttl; returns whether the key exists
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool>;
+
+    /// time left until key expires, `None` if it never does;
+    /// fails with `KvsError::KeyNotFound` if key does not exist
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// remove the expiry of key; returns whether it had one
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool>;
+
+    /// iterate over at most `limit` keys in range, reading values lazily
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_>;
+
+    /// iterate over the keys that start with prefix, reading values lazily
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> ScanBytes<'_>;
+
+    /// iterate over at most `limit` keys in range in reverse key order
+    fn scan_rev_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_>;
+
     /// set key
-     fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// get key
-     fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 
     /// remove key
-     fn remove(&mut self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// string form of `compare_and_swap_bytes`
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// set key if it does not exist; returns whether it was set
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// set key if it exists; returns whether it was set
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// string form of `incr_by_bytes`
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr_by_bytes(key.into_bytes(), delta)
+    }
+
+    /// set key to a value that expires after ttl
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// make an existing key expire after ttl; returns whether the key exists
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        self.expire_bytes(key.into_bytes(), ttl)
+    }
+
+    /// string form of `ttl_bytes`
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// remove the expiry of key; returns whether it had one
+    fn persist(&self, key: String) -> Result<bool> {
+        self.persist_bytes(key.into_bytes())
+    }
+
+    /// string form of `scan_bytes`; keys are ordered by their UTF-8 bytes
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Scan<'_> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Box::new(self.scan_bytes(range, limit).map(string_pair))
+    }
+
+    /// iterate over the keys that start with prefix, reading values lazily
+    fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        Box::new(self.scan_prefix_bytes(prefix.as_bytes()).map(string_pair))
+    }
+
+    /// string form of `scan_rev_bytes`
+    fn scan_rev<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Scan<'_> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Box::new(self.scan_rev_bytes(range, limit).map(string_pair))
+    }
+}
+
+/// Converts a bound of a string range to a bound of a byte range.
+pub(crate) fn bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    bound.map(|key| key.as_bytes().to_vec())
+}
+
+/// Converts a binary key/value pair to strings.
+pub(crate) fn string_pair(entry: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = entry?;
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
@@ -1,3 +1,6 @@
+// `failure_derive` expands into impl blocks inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::{io, string::FromUtf8Error};
 
@@ -17,9 +20,54 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A log record failed to decode or did not match its checksum.
+    #[fail(
+        display = "corrupted log record in generation {} at offset {}: {}",
+        gen, offset, reason
+    )]
+    Corruption {
+        /// Generation of the log file.
+        gen: u64,
+        /// Byte offset of the record in the log file.
+        offset: u64,
+        /// What is wrong with the record.
+        reason: String,
+    },
+    /// A key, a value or a batch is too large to fit in a log record.
+    #[fail(display = "{} bytes are too large for a log record", _0)]
+    RecordTooLarge(u64),
+    /// The value of a key is not an integer, or the result of an increment is out of range.
+    #[fail(display = "value is not an integer or out of range")]
+    NotAnInteger,
+    /// A write panicked and left the log writer in an unknown state, so the
+    /// store refuses further writes until it is reopened.
+    #[fail(display = "a write panicked, reopen the store to write again")]
+    WriterFailed,
+    /// A key read by a transaction was modified by another writer before the
+    /// transaction committed.
+    #[fail(display = "transaction conflict")]
+    Conflict,
+    /// 客户端与服务器之间的数据帧不合法
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
     /// 服务器返回内容编码错误
     #[fail(display = "server encode error")]
     EncodeError(FromUtf8Error),
+    /// 数据目录已由另一个存储引擎创建
+    #[fail(
+        display = "data directory was created by the '{}' engine, but '{}' was requested",
+        recorded, requested
+    )]
+    WrongEngine {
+        /// 数据目录中记录的引擎
+        recorded: String,
+        /// 启动时指定的引擎
+        requested: String,
+    },
+    /// Sled error.
+    #[cfg(feature = "sled")]
+    #[fail(display = "sled error: {}", _0)]
+    Sled(#[cause] sled::Error),
 }
 
 impl From<io::Error> for KvsError {
@@ -40,5 +88,12 @@ impl From<FromUtf8Error> for KvsError {
     }
 }
 
+#[cfg(feature = "sled")]
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
+
 /// Result type for kvs.
 pub type Result<T> = std::result::Result<T, KvsError>;
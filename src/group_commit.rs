@@ -0,0 +1,180 @@
+//! Group commit of concurrent writes.
+//!
+//! Writers enqueue their items and wait. The writer at the front of the queue
+//! becomes the leader: it takes a group of queued items, writes them all at
+//! once, and hands every writer in the group its own result. Writers that
+//! arrive while a leader is busy form the next group.
+//!
+//! If the write of a leader panics, the other writers of its group get no
+//! result and the next writer in the queue becomes the leader.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A queue that lets concurrent writers share a single write.
+pub(crate) struct GroupCommit<T, R> {
+    state: Mutex<State<T, R>>,
+    cond: Condvar,
+    // how long a leader waits for more writers before taking its group.
+    delay: Duration,
+    // the maximum total size of a group, a group has at least one item.
+    max_bytes: u64,
+}
+
+struct State<T, R> {
+    queue: VecDeque<Pending<T>>,
+    // results of finished writes, until their writers pick them up. `None`
+    // if the write of the group panicked.
+    results: HashMap<u64, Option<R>>,
+    next_id: u64,
+    // whether a leader is writing a group.
+    leading: bool,
+}
+
+struct Pending<T> {
+    id: u64,
+    item: T,
+    size: u64,
+}
+
+impl<T, R> GroupCommit<T, R> {
+    /// Creates a queue whose leaders wait `delay` for more writers and write
+    /// at most `max_bytes` in a group.
+    pub(crate) fn new(delay: Duration, max_bytes: u64) -> Self {
+        GroupCommit {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                results: HashMap::new(),
+                next_id: 0,
+                leading: false,
+            }),
+            cond: Condvar::new(),
+            delay,
+            max_bytes,
+        }
+    }
+
+    /// Commits `item` of the given size, possibly together with the items of
+    /// other threads.
+    ///
+    /// `write` is called by the leader with the items of a group in the order
+    /// they were queued, and must return one result per item.
+    ///
+    /// Returns `None` if `write` was called by another thread and panicked.
+    pub(crate) fn commit<F>(&self, item: T, size: u64, write: F) -> Option<R>
+    where
+        F: FnOnce(Vec<T>) -> Vec<R>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(Pending { id, item, size });
+
+        loop {
+            if let Some(result) = state.results.remove(&id) {
+                return result;
+            }
+            if !state.leading && state.queue.front().map(|p| p.id) == Some(id) {
+                break;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+
+        state.leading = true;
+        if !self.delay.is_zero() {
+            drop(state);
+            thread::sleep(self.delay);
+            state = self.state.lock().unwrap();
+        }
+
+        let mut ids = Vec::new();
+        let mut items = Vec::new();
+        let mut bytes = 0;
+        while let Some(pending) = state.queue.front() {
+            if !ids.is_empty() && bytes + pending.size > self.max_bytes {
+                break;
+            }
+            let pending = state.queue.pop_front().unwrap();
+            bytes += pending.size;
+            ids.push(pending.id);
+            items.push(pending.item);
+        }
+        drop(state);
+
+        // the leader is at the front of the queue, so its item comes first.
+        let mut leader = Leader {
+            group: self,
+            followers: ids.split_off(1),
+        };
+        let mut results = write(items).into_iter();
+        let own_result = results.next();
+
+        let mut state = self.state.lock().unwrap();
+        for (follower, result) in leader.followers.drain(..).zip(results) {
+            state.results.insert(follower, Some(result));
+        }
+        drop(state);
+        drop(leader);
+        own_result
+    }
+}
+
+// Hands the leadership over when the leader is done with its group, even if
+// its write panicked. Followers left without a result get `None`.
+struct Leader<'a, T, R> {
+    group: &'a GroupCommit<T, R>,
+    followers: Vec<u64>,
+}
+
+impl<T, R> Drop for Leader<'_, T, R> {
+    fn drop(&mut self) {
+        let mut state = self
+            .group
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        state.leading = false;
+        for follower in self.followers.drain(..) {
+            state.results.insert(follower, None);
+        }
+        self.group.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GroupCommit;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Writers grouped with a leader whose write panics should fail instead of
+    // waiting forever, and the next writer should become the leader.
+    #[test]
+    fn panicking_write() {
+        let group = Arc::new(GroupCommit::new(Duration::from_millis(200), u64::MAX));
+
+        let leader = {
+            let group = Arc::clone(&group);
+            thread::spawn(move || {
+                group.commit(0, 1, |_: Vec<u32>| -> Vec<u32> { panic!("write failed") })
+            })
+        };
+        // join the group while the leader waits for more writers.
+        thread::sleep(Duration::from_millis(50));
+        let followers: Vec<_> = (1..4)
+            .map(|item| {
+                let group = Arc::clone(&group);
+                thread::spawn(move || group.commit(item, 1, |items| items))
+            })
+            .collect();
+
+        assert!(leader.join().is_err());
+        for follower in followers {
+            assert_eq!(follower.join().unwrap(), None);
+        }
+        assert_eq!(group.commit(4, 1, |items| items), Some(4));
+    }
+}
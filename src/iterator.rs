@@ -0,0 +1,129 @@
+//! Bidirectional iteration over a view of a `KvStore`.
+
+use std::ops::Bound;
+
+use crate::engine::string_pair;
+use crate::kv::ViewPin;
+use crate::{KvStore, Result};
+
+/// A cursor over the key/value pairs of a `KvStore` in key order, created by
+/// `KvStore::iter`.
+///
+/// The iterator reads a view of the store as of its creation: writes made
+/// while it is open are not seen, and the values it reads are kept in the log
+/// until it is dropped. It is positioned between two keys, so `next` returns
+/// the pair after the position and `prev` the pair before it, each moving
+/// past the pair returned. `next_bytes` and `prev_bytes` do the same for keys
+/// and values that are not UTF-8.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// // the latest 10 events before noon.
+/// let mut iter = store.iter();
+/// iter.seek("event:12:00");
+/// let latest = (0..10).map_while(|_| iter.prev()).collect::<Result<Vec<_>>>()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvIterator<'a> {
+    store: &'a KvStore,
+    view: ViewPin,
+    position: Position,
+}
+
+/// Where a `KvIterator` is between two keys.
+enum Position {
+    // before the first key.
+    Start,
+    // after the last key.
+    End,
+    // right before the key, which may not exist.
+    Before(Vec<u8>),
+    // right after the key, which may not exist.
+    After(Vec<u8>),
+}
+
+impl<'a> KvIterator<'a> {
+    pub(crate) fn new(store: &'a KvStore, view: ViewPin) -> KvIterator<'a> {
+        KvIterator {
+            store,
+            view,
+            position: Position::Start,
+        }
+    }
+
+    /// Moves before the first key that is not less than `key`.
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) {
+        self.position = Position::Before(key.as_ref().to_vec());
+    }
+
+    /// Moves after the last key that is not greater than `key`.
+    pub fn seek_for_prev(&mut self, key: impl AsRef<[u8]>) {
+        self.position = Position::After(key.as_ref().to_vec());
+    }
+
+    /// Moves before the first key.
+    pub fn seek_to_first(&mut self) {
+        self.position = Position::Start;
+    }
+
+    /// Moves after the last key.
+    pub fn seek_to_last(&mut self) {
+        self.position = Position::End;
+    }
+
+    /// Returns the pair before the position and moves before it, or `None` at
+    /// the first key.
+    pub fn prev(&mut self) -> Option<Result<(String, String)>> {
+        self.prev_bytes().map(string_pair)
+    }
+
+    /// Returns the binary pair after the position and moves after it, or
+    /// `None` at the last key.
+    pub fn next_bytes(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let from = match &self.position {
+            Position::Start => Bound::Unbounded,
+            Position::End => return None,
+            Position::Before(key) => Bound::Included(key.as_slice()),
+            Position::After(key) => Bound::Excluded(key.as_slice()),
+        };
+        let entry = self.store.seek_at(from, false, self.view.seq());
+        self.position = match &entry {
+            Some(Ok((key, _))) => Position::After(key.clone()),
+            Some(Err(_)) => return entry,
+            None => Position::End,
+        };
+        entry
+    }
+
+    /// Returns the binary pair before the position and moves before it, or
+    /// `None` at the first key.
+    pub fn prev_bytes(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let from = match &self.position {
+            Position::Start => return None,
+            Position::End => Bound::Unbounded,
+            Position::Before(key) => Bound::Excluded(key.as_slice()),
+            Position::After(key) => Bound::Included(key.as_slice()),
+        };
+        let entry = self.store.seek_at(from, true, self.view.seq());
+        self.position = match &entry {
+            Some(Ok((key, _))) => Position::Before(key.clone()),
+            Some(Err(_)) => return entry,
+            None => Position::Start,
+        };
+        entry
+    }
+}
+
+impl Iterator for KvIterator<'_> {
+    type Item = Result<(String, String)>;
+
+    /// Returns the pair after the position and moves after it, or `None` at
+    /// the last key.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_bytes().map(string_pair)
+    }
+}
@@ -1,121 +1,426 @@
+use std::cell::RefCell;
+use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize};
+use crossbeam_skiplist::SkipMap;
+use serde::Deserialize;
 use serde_json::Deserializer;
 
-use crate::{KvsEngine, KvsError, Result};
+use crate::group_commit::GroupCommit;
+use crate::iterator::KvIterator;
+use crate::record::{self, RecordError};
+use crate::snapshot::Snapshot;
+use crate::transaction::Transaction;
+use crate::{BatchOp, KvsEngine, KvsError, Result, ScanBytes, WriteBatch, LOGGER};
+use slog::error;
 use std::ffi::OsStr;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
-/// The `KvStore` stores string key/value pairs.
+// the number of keys checked for expiry in each round of the background sampler.
+const EXPIRY_SAMPLE_SIZE: usize = 20;
+
+/// Map from keys to the versions of their values in the log, oldest first.
+///
+/// A key keeps one skiplist node for its whole life and new versions are added
+/// in place, because replacing a node makes the key briefly invisible to readers.
+/// Each version carries the sequence number of the write that made it, which
+/// transactions use to detect concurrent modifications. Older versions are only
+/// kept while an open view may read them, so most keys have a single version.
+type Index = SkipMap<Vec<u8>, RwLock<Vec<Version>>>;
+
+/// When writes to the log are synced to the disk.
+///
+/// A write that is only flushed survives a crash of the process, but may be
+/// lost on a power failure until it is synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync explicitly and leave it to the operating system.
+    Never,
+    /// Sync before every `set` or `remove` returns.
+    Always,
+    /// Sync from a background thread at the given interval.
+    Interval(Duration),
+    /// Sync once the given number of bytes has been written since the last sync.
+    Bytes(u64),
+}
+
+/// Options to open a `KvStore` with.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result, SyncPolicy};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions {
+///     sync_policy: SyncPolicy::Always,
+///     ..KvStoreOptions::default()
+/// };
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// When writes are synced to the disk, `SyncPolicy::Never` by default.
+    pub sync_policy: SyncPolicy,
+    /// How long a write waits for concurrent writes to join its group commit,
+    /// zero by default.
+    ///
+    /// Writes that arrive while a group is being written always form the next
+    /// group, so a delay only trades latency for larger groups.
+    pub group_commit_delay: Duration,
+    /// The maximum total size of keys and values written in one group commit,
+    /// 1 MiB by default.
+    pub group_commit_bytes: u64,
+    /// How often a background thread checks keys for expiry and evicts the
+    /// expired ones, 100 ms by default.
+    pub expiry_sample_interval: Duration,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::Never,
+            group_commit_delay: Duration::from_millis(0),
+            group_commit_bytes: 1024 * 1024,
+            expiry_sample_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The `KvStore` stores binary key/value pairs.
+///
+/// The `String` methods of `KvsEngine` are a convenience layer over the `_bytes`
+/// methods, so keys and values written as bytes need not be valid UTF-8.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// A `SkipMap` in memory stores the keys and the value locations for fast query.
+///
+/// A key may expire. The absolute expiry is written to the log with the value,
+/// `get` hides expired keys, and a background thread evicts them from the index
+/// so that the next compaction drops them from the log.
+///
+/// A `KvStore` can be cloned cheaply and the clones can be sent to other threads.
+/// All clones share the same index and log writer, while each clone keeps its
+/// own file readers. The index is a lock-free skiplist, so `get` never waits on
+/// the writer lock or on the file I/O of other threads.
+///
+/// A write that panics leaves the log in an unknown state, so every later
+/// write fails with `KvsError::WriterFailed` until the store is reopened.
+/// Reads keep working.
 ///
 /// ```rust
-/// # use kvs::{KvStore, Result};
+/// # use kvs::{KvStore, KvsEngine, Result};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
-/// let mut store = KvStore::open(current_dir()?)?;
+/// let store = KvStore::open(current_dir()?)?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
 /// # Ok(())
 /// # }
 /// ```
+#[derive(Clone)]
 pub struct KvStore {
-    // directory for the log and other data.
-    path: PathBuf,
-    // map generation number to the file reader.
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    // writer of the current log.
-    writer: BufWriterWithPos<File>,
-    current_gen: u64,
-    index: BTreeMap<String, CommandPos>,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction.
-    uncompacted: u64,
+    // map from keys to the versions of their values in the log.
+    index: Arc<Index>,
+    // readers of the log files, owned by this clone.
+    reader: KvStoreReader,
+    // the only writer, shared by all clones.
+    writer: Arc<Mutex<KvStoreWriter>>,
+    // queue of concurrent writes waiting to be written together.
+    group_commit: Arc<GroupCommit<LogWrite, Result<Vec<bool>>>>,
+    // views open on the store, shared by all clones and the writer.
+    views: Arc<Views>,
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
-        }
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.commit(LogWrite::new(vec![Command::set(key, value)]))
+            .map(drop)
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.commit(LogWrite::new(vec![Command::set_expiring(
+            key,
+            value,
+            Some(expiry(ttl)),
+        )]))
+        .map(drop)
     }
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(&key)?.value)
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.commit(LogWrite::new(vec![Command::remove(key)]))
+            .map(drop)
+    }
+
+    /// Applies a batch of writes atomically.
+    ///
+    /// The batch is logged as a single record, so after a crash either all of
+    /// it or none of it is loaded. Concurrent `get`s may observe a part of the
+    /// batch while it is being applied to the index.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` without writing anything if the batch
+    /// removes a key that does not exist, unless it is removed with `delete`.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write(&self, batch: WriteBatch) -> Result<Vec<bool>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.commit(LogWrite::from_batch(batch))
+    }
+
+    /// Replaces the value of a key with `new` if it is `expected`.
+    ///
+    /// `None` stands for a missing key, so `new` being `None` removes the key.
+    /// Returns whether the value was replaced.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write_if(key, |current| current == expected.as_deref(), new)
+    }
+
+    /// Sets the value of a key if it does not exist.
+    ///
+    /// Returns whether the value was set.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.write_if(key, |current| current.is_none(), Some(value))
+    }
+
+    /// Sets the value of a key if it exists.
+    ///
+    /// Returns whether the value was set.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.write_if(key, |current| current.is_some(), Some(value))
+    }
+
+    /// Adds `delta` to the integer value of a key, a missing key counting as 0.
+    ///
+    /// The new value is written with a single log append. Returns the new value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value is not an integer or the
+    /// result overflows.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        loop {
+            let mut txn = self.transaction();
+            let (current, expires_at) = txn.read(&key)?.unzip();
+            let value = incr(current.as_deref(), delta)?;
+            // like in Redis, a counter keeps its expiry.
+            txn.set_expiring(
+                key.clone(),
+                value.to_string().into_bytes(),
+                expires_at.flatten(),
+            );
+            match txn.commit() {
+                Ok(()) => return Ok(value),
+                Err(KvsError::Conflict) => {}
+                Err(e) => return Err(e),
             }
-        } else {
-            Ok(None)
         }
     }
 
-    /// Removes a given key.
+    /// Makes an existing key expire after `ttl`.
+    ///
+    /// Returns whether the key exists.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.rewrite_expiry(key, Some(ttl))
+    }
+
+    /// Returns how long until a key expires, `None` if it never does.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        let expires_at = match self
+            .index
+            .get(&key)
+            .and_then(|entry| latest(entry.value()).pos)
+        {
+            Some(cmd_pos) => cmd_pos.expires_at,
+            None => return Err(KvsError::KeyNotFound),
+        };
+        match expires_at {
+            Some(expires_at) if expires_at <= now => Err(KvsError::KeyNotFound),
+            Some(expires_at) => Ok(Some(Duration::from_millis(expires_at - now))),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the expiry of a key.
+    ///
+    /// Returns whether the key existed and had an expiry.
+    ///
+    /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.rewrite_expiry(key, None)
+    }
+
+    /// Iterates over at most `limit` key/value pairs with keys in `range`, in
+    /// key order.
+    ///
+    /// Values are read from the log as the iterator advances. The scan is not
+    /// a snapshot: it sees writes made to keys it has not reached yet.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_> {
+        Box::new(IndexScan {
+            store: self,
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            prefix: None,
+            remaining: limit,
+        })
+    }
+
+    /// Iterates over the key/value pairs whose keys start with `prefix`, in
+    /// key order.
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> ScanBytes<'_> {
+        Box::new(IndexScan {
+            store: self,
+            next: Bound::Included(prefix.to_vec()),
+            end: Bound::Unbounded,
+            prefix: Some(prefix.to_vec()),
+            remaining: usize::MAX,
+        })
+    }
+
+    /// Iterates over at most `limit` key/value pairs with keys in `range`, in
+    /// reverse key order.
+    ///
+    /// Unlike `scan`, it reads a view of the store as of the call, which later
+    /// writes do not change.
+    fn scan_rev_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_> {
+        let mut iter = self.iter();
+        match range.end_bound() {
+            Bound::Included(key) => iter.seek_for_prev(key),
+            Bound::Excluded(key) => iter.seek(key),
+            Bound::Unbounded => iter.seek_to_last(),
+        }
+        let start = range.start_bound().cloned();
+        let entries =
+            std::iter::from_fn(move || iter.prev_bytes()).take_while(move |entry| match entry {
+                Ok((key, _)) => (start.as_ref(), Bound::Unbounded).contains(key),
+                Err(_) => true,
+            });
+        Box::new(entries.take(limit))
+    }
+}
+
+/// An iterator over the index that reads each value when it is reached.
+struct IndexScan<'a> {
+    store: &'a KvStore,
+    // bounds of the keys that have not been reached yet.
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // the scan ends at the first key without this prefix.
+    prefix: Option<Vec<u8>>,
+    remaining: usize,
+}
+
+impl Iterator for IndexScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            // the key is looked up again on every step rather than holding a
+            // skiplist iterator, so the scan does not borrow the index.
+            let key = self
+                .store
+                .index
+                .range::<Vec<u8>, _>((self.next.clone(), self.end.clone()))
+                .next()?
+                .key()
+                .clone();
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    return None;
+                }
+            }
+            self.next = Bound::Excluded(key.clone());
+            match self.store.get_versioned(&key) {
+                Ok(Versioned {
+                    value: Some(value), ..
+                }) => {
+                    self.remaining -= 1;
+                    return Some(Ok((key, value)));
+                }
+                // removed or expired since it was found.
+                Ok(_) => {}
+                Err(e) => {
+                    self.remaining = 0;
+                    return Some(Err(e));
+                }
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
+        None
     }
 }
 
@@ -128,107 +433,841 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        fs::create_dir_all(&path)?;
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
 
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+        let mut readers = BTreeMap::new();
+        let index = Arc::new(SkipMap::new());
 
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
 
         for &gen in &gen_list {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
+            let (stale, torn) = load(gen, &mut reader, &index)?;
+            uncompacted += stale;
+            if let Some(torn) = torn {
+                // only the newest generation can end with a partial write of a crash.
+                if Some(&gen) != gen_list.last() {
+                    return Err(KvsError::Corruption {
+                        gen,
+                        offset: torn.offset,
+                        reason: torn.reason,
+                    });
+                }
+                truncate_torn_tail(&path, gen, &torn)?;
+            }
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let writer = new_log_file(&path, current_gen)?;
+        if options.sync_policy != SyncPolicy::Never {
+            sync_dir(&path)?;
+        }
 
-        Ok(KvStore {
-            path,
-            readers,
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
+        let views = Arc::new(Views {
+            state: Mutex::new(ViewState {
+                last_seq: 0,
+                open: BTreeMap::new(),
+                retired: Vec::new(),
+            }),
+            path: Arc::clone(&path),
+            safe_point: Arc::clone(&reader.safe_point),
+        });
+        let writer = KvStoreWriter {
+            reader: reader.clone(),
             writer,
             current_gen,
-            index,
             uncompacted,
+            sync_policy: options.sync_policy,
+            unsynced: 0,
+            failed: false,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            views: Arc::clone(&views),
+        };
+        let writer = Arc::new(Mutex::new(writer));
+
+        if let SyncPolicy::Interval(interval) = options.sync_policy {
+            let writer = Arc::downgrade(&writer);
+            thread::Builder::new()
+                .name("kvs-flusher".to_owned())
+                .spawn(move || background_sync(writer, interval))?;
+        }
+        let expiry_writer = Arc::downgrade(&writer);
+        let interval = options.expiry_sample_interval;
+        thread::Builder::new()
+            .name("kvs-expirer".to_owned())
+            .spawn(move || background_expire(expiry_writer, interval))?;
+
+        Ok(KvStore {
+            index,
+            reader,
+            writer,
+            group_commit: Arc::new(GroupCommit::new(
+                options.group_commit_delay,
+                options.group_commit_bytes,
+            )),
+            views,
         })
     }
 
+    /// Starts an optimistic transaction.
+    ///
+    /// See `Transaction` for how its reads are validated.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    /// Returns an iterator over a view of the store as of now, positioned
+    /// before the first key.
+    ///
+    /// See `KvIterator` for how to move it in both directions.
+    pub fn iter(&self) -> KvIterator<'_> {
+        KvIterator::new(self, self.open_view())
+    }
+
+    /// Returns a read-only snapshot of the store as of now.
+    ///
+    /// The values it reads are kept in the log until it is dropped, so
+    /// long-lived snapshots delay reclaiming the space of compacted files.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.clone(), self.open_view())
+    }
+
+    /// Opens a view of the store at the last write applied to the index.
+    pub(crate) fn open_view(&self) -> ViewPin {
+        let mut state = self.views.state.lock().unwrap();
+        let seq = state.last_seq;
+        *state.open.entry(seq).or_insert(0) += 1;
+        ViewPin {
+            views: Arc::clone(&self.views),
+            seq,
+        }
+    }
+
+    /// Gets the value of a key as of the write with sequence number `seq`.
+    ///
+    /// The versions read by a view stay in the index while the view is open.
+    pub(crate) fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        loop {
+            let version = match self.index.get(key) {
+                Some(entry) => visible_at(&entry.value().read().unwrap(), seq).cloned(),
+                None => None,
+            };
+            let cmd_pos = match version.and_then(|version| version.pos) {
+                Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => cmd_pos,
+                _ => return Ok(None),
+            };
+            if let Some(value) = self.read_value(&cmd_pos)? {
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    /// Finds the first key after `from` in key order, or the last key before it
+    /// if `reverse`, that has a value as of the write with sequence number `seq`.
+    pub(crate) fn seek_at(
+        &self,
+        from: Bound<&[u8]>,
+        reverse: bool,
+        seq: u64,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let mut from = from.map(<[u8]>::to_vec);
+        loop {
+            let bound = from.as_ref().map(Vec::as_slice);
+            let key = if reverse {
+                self.index.upper_bound(bound)
+            } else {
+                self.index.lower_bound(bound)
+            }?
+            .key()
+            .clone();
+            match self.get_at(&key, seq) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // written after the view, removed or expired.
+                Ok(None) => from = Bound::Excluded(key),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Writes `new` to a key, or removes it if `new` is `None`, if `cond` holds
+    /// for its current value.
+    ///
+    /// The check and the write form a transaction, which is retried until no
+    /// other writer modifies the key in between. Returns whether it was written.
+    fn write_if<F>(&self, key: Vec<u8>, cond: F, new: Option<Vec<u8>>) -> Result<bool>
+    where
+        F: Fn(Option<&[u8]>) -> bool,
+    {
+        loop {
+            let mut txn = self.transaction();
+            let current = txn.get_bytes(key.clone())?;
+            if !cond(current.as_deref()) {
+                return Ok(false);
+            }
+            match (&new, current) {
+                (Some(value), _) => txn.set_bytes(key.clone(), value.clone()),
+                (None, Some(_)) => txn.remove_bytes(key.clone())?,
+                // removing a missing key leaves it missing.
+                (None, None) => {}
+            }
+            match txn.commit() {
+                Ok(()) => return Ok(true),
+                Err(KvsError::Conflict) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes the value of a key again with a new expiry, or without one if
+    /// `ttl` is `None`.
+    ///
+    /// Returns whether the key exists, and for removing the expiry whether it
+    /// had one.
+    fn rewrite_expiry(&self, key: Vec<u8>, ttl: Option<Duration>) -> Result<bool> {
+        loop {
+            let mut txn = self.transaction();
+            let value = match txn.read(&key)? {
+                Some((_, None)) if ttl.is_none() => return Ok(false),
+                Some((value, _)) => value,
+                None => return Ok(false),
+            };
+            txn.set_expiring(key.clone(), value, ttl.map(expiry));
+            match txn.commit() {
+                Ok(()) => return Ok(true),
+                Err(KvsError::Conflict) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Gets the value of a key together with the index entry it was read from.
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> Result<Versioned> {
+        loop {
+            // a removed key may keep its entry for open views, but it is
+            // missing like a key without one.
+            let (seq, cmd_pos) = match self.index.get(key).map(|entry| latest(entry.value())) {
+                Some(Version {
+                    seq,
+                    pos: Some(cmd_pos),
+                }) => (seq, cmd_pos),
+                _ => {
+                    return Ok(Versioned {
+                        value: None,
+                        expires_at: None,
+                        seq: None,
+                    })
+                }
+            };
+            // an expired key is missing, but its entry is still in the index.
+            if cmd_pos.is_expired(now_millis()) {
+                return Ok(Versioned {
+                    value: None,
+                    expires_at: None,
+                    seq: Some(seq),
+                });
+            }
+            if let Some(value) = self.read_value(&cmd_pos)? {
+                return Ok(Versioned {
+                    value: Some(value),
+                    expires_at: cmd_pos.expires_at,
+                    seq: Some(seq),
+                });
+            }
+        }
+    }
+
+    /// Reads the value at the given position.
+    ///
+    /// Returns `None` if a compaction moved the value and removed the log file
+    /// between the index lookup and the read; the index already points to the
+    /// new position, so the caller looks it up again.
+    fn read_value(&self, cmd_pos: &CommandPos) -> Result<Option<Vec<u8>>> {
+        match self.reader.read_command(cmd_pos) {
+            Ok(Command::Set { value, .. }) => Ok(Some(value)),
+            Ok(Command::Remove { .. }) => Err(KvsError::UnexpectedCommandType),
+            Err(KvsError::Io(ref e))
+                if e.kind() == io::ErrorKind::NotFound && self.reader.is_stale(cmd_pos.gen) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the commands of a transaction if none of the keys it read has
+    /// been modified since.
+    ///
+    /// `reads` holds the sequence number seen for each key read, `None` for a
+    /// key that did not exist.
+    pub(crate) fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        cmds: Vec<Command>,
+    ) -> Result<()> {
+        let optional = vec![false; cmds.len()];
+        self.commit(LogWrite {
+            cmds,
+            reads,
+            optional,
+        })
+        .map(drop)
+    }
+
+    /// Writes commands to the log atomically, grouped with the concurrent
+    /// writes of other clones.
+    ///
+    /// Returns whether each command was written, which is false only for an
+    /// optional remove of a missing key.
+    fn commit(&self, write: LogWrite) -> Result<Vec<bool>> {
+        let size = write
+            .cmds
+            .iter()
+            .map(|cmd| match cmd {
+                Command::Set { key, value, .. } => key.len() + value.len(),
+                Command::Remove { key } => key.len(),
+            })
+            .sum::<usize>();
+        self.group_commit
+            .commit(write, size as u64, |writes| {
+                match lock_writer(&self.writer) {
+                    Ok(mut writer) => writer.write_group(writes),
+                    Err(_) => writes.iter().map(|_| Err(KvsError::WriterFailed)).collect(),
+                }
+            })
+            .unwrap_or(Err(KvsError::WriterFailed))
+    }
+
     /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+    pub fn compact(&self) -> Result<()> {
+        lock_writer(&self.writer)?.compact()
+    }
+}
 
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+/// A single thread reader.
+///
+/// Each `KvStore` clone has its own `KvStoreReader`, so the file handles are
+/// never shared between threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // generation of the latest compaction file. Log files with a smaller
+    // generation have been removed and their readers can be closed.
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+}
 
-        let mut new_pos = 0; // pos in the new log file.
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            // a new clone opens its own file handles lazily.
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Returns whether the log file of the given generation has been replaced by compaction.
+    fn is_stale(&self, gen: u64) -> bool {
+        gen < self.safe_point.load(Ordering::SeqCst)
+    }
+
+    /// Closes file handles of the log files that have been removed by compaction.
+    ///
+    /// A handle that is still open keeps working after its file is deleted,
+    /// so a reader only drops it once it sees the new safe point.
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        while let Some((&gen, _)) = readers.iter().next() {
+            if gen >= safe_point {
+                break;
             }
+            readers.remove(&gen);
+        }
+    }
+
+    /// Reads the log file at the given `CommandPos` and hands the bytes of
+    /// the command to `f`.
+    fn read_and<F, R>(&self, cmd_pos: &CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::open(log_path(&self.path, cmd_pos.gen))?;
+                entry.insert(BufReaderWithPos::new(file)?)
+            }
+        };
+        if reader.pos != cmd_pos.pos {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        }
+        f(reader.take(cmd_pos.len))
+    }
+
+    /// Reads the command at the given `CommandPos`.
+    fn read_command(&self, cmd_pos: &CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            let mut buf = Vec::with_capacity(cmd_pos.len as usize);
+            cmd_reader.read_to_end(&mut buf)?;
+            decode_command(&buf).map_err(|reason| KvsError::Corruption {
+                gen: cmd_pos.gen,
+                offset: cmd_pos.pos,
+                reason,
+            })
+        })
+    }
+}
+
+/// The writer of the log, guarded by a mutex shared by all `KvStore` clones.
+struct KvStoreWriter {
+    // a reader used during compaction.
+    reader: KvStoreReader,
+    // writer of the current log.
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction.
+    uncompacted: u64,
+    sync_policy: SyncPolicy,
+    // the number of bytes written to the current log since the last sync.
+    unsynced: u64,
+    // set when a failed append could not be rolled back, so the current log
+    // may end in a partial record that later appends must not follow.
+    failed: bool,
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+    views: Arc<Views>,
+}
+
+impl KvStoreWriter {
+    /// Writes a group of writes to the log with a single flush and sync.
+    ///
+    /// Each write is a list of commands applied atomically. Returns one result
+    /// per write. A write that removes a missing key or whose reads are stale
+    /// fails on its own, while a failed append fails every write of the group
+    /// and leaves neither the log nor the index changed.
+    fn write_group(&mut self, writes: Vec<LogWrite>) -> Vec<Result<Vec<bool>>> {
+        let mut results = Vec::with_capacity(writes.len());
+        let mut written = Vec::with_capacity(writes.len());
+        let mut records = Vec::with_capacity(writes.len());
+        let now = now_millis();
+        // whether keys exist after the earlier writes of the group.
+        let mut exists = HashMap::new();
+        for LogWrite {
+            cmds,
+            reads,
+            optional,
+        } in writes
+        {
+            // the reads happened before the group was taken, so a key written by
+            // an earlier write of the group has been modified since.
+            let conflict = reads.iter().any(|(key, seq)| {
+                exists.contains_key(key)
+                    || self.index.get(key).and_then(|entry| {
+                        let version = latest(entry.value());
+                        version.pos.is_some().then_some(version.seq)
+                    }) != *seq
+            });
+            if conflict {
+                results.push(Err(KvsError::Conflict));
+                continue;
+            }
+            // whether keys exist after the earlier commands of this write.
+            let mut write_exists = HashMap::new();
+            let mut missing = false;
+            let mut applied = Vec::with_capacity(cmds.len());
+            let mut kept = Vec::with_capacity(cmds.len());
+            for (cmd, optional) in cmds.into_iter().zip(optional) {
+                let (key, is_set) = match &cmd {
+                    Command::Set { key, .. } => (key, true),
+                    Command::Remove { key } => (key, false),
+                };
+                let key_exists = write_exists
+                    .get(key)
+                    .or_else(|| exists.get(key))
+                    .copied()
+                    .unwrap_or_else(|| {
+                        self.index
+                            .get(key)
+                            .and_then(|entry| latest(entry.value()).pos)
+                            .is_some_and(|cmd_pos| !cmd_pos.is_expired(now))
+                    });
+                if !is_set && !key_exists {
+                    if optional {
+                        applied.push(false);
+                        continue;
+                    }
+                    missing = true;
+                    break;
+                }
+                write_exists.insert(key.clone(), is_set);
+                applied.push(true);
+                kept.push(cmd);
+            }
+            if missing {
+                results.push(Err(KvsError::KeyNotFound));
+                continue;
+            }
+            // an oversize record fails its write before anything is appended.
+            let record = match record::encode_write(&kept) {
+                Ok(record) => record,
+                Err(e) => {
+                    results.push(Err(e));
+                    continue;
+                }
+            };
+            exists.extend(write_exists);
+            results.push(Ok(applied));
+            records.push(record);
+            written.push(kept);
+        }
+
+        let ranges = match self.append(&records) {
+            Ok(ranges) => ranges,
+            Err(e) => return fail_group(results, &e),
+        };
+        // the group is on the disk, now make it visible to readers. New views
+        // wait for the whole group, so they never see a part of a write.
+        let mut views = self.views.state.lock().unwrap();
+        let oldest_view = views.oldest();
+        for (cmd, range) in written.into_iter().flatten().zip(ranges) {
+            views.last_seq += 1;
+            let seq = views.last_seq;
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                } => {
+                    let version = Version {
+                        seq,
+                        pos: Some(CommandPos::new(self.current_gen, range, expires_at)),
+                    };
+                    if let Some(old_cmd) = add_version(&self.index, key, version, oldest_view) {
+                        self.uncompacted += old_cmd.len;
+                    }
+                }
+                Command::Remove { key } => {
+                    let old_cmd = match oldest_view {
+                        // open views may still read the removed value.
+                        Some(_) => {
+                            add_version(&self.index, key, Version { seq, pos: None }, oldest_view)
+                        }
+                        None => self
+                            .index
+                            .remove(&key)
+                            .and_then(|entry| latest(entry.value()).pos),
+                    };
+                    if let Some(old_cmd) = old_cmd {
+                        self.uncompacted += old_cmd.len;
+                    }
+                    // the "remove" command itself can be deleted in the next compaction.
+                    self.uncompacted += range.end - range.start;
+                }
+            }
+        }
+        drop(views);
+
+        // the group is written and visible, so a failed compaction does not
+        // fail it, and is tried again after the next group.
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            if let Err(e) = self.compact() {
+                error!(LOGGER, "Failed to compact the log: {}", e);
+            }
+        }
+        results
+    }
+
+    /// Appends the records of writes, as encoded by `record::encode_write`, to
+    /// the current log and syncs them according to the policy.
+    ///
+    /// Returns the range of the record of each command in the log. On failure
+    /// the log is cut back to where it was, so no part of the records is left.
+    fn append(&mut self, records: &[(Vec<u8>, Vec<Range<u64>>)]) -> Result<Vec<Range<u64>>> {
+        if self.failed {
+            return Err(KvsError::WriterFailed);
+        }
+        let start = self.writer.pos;
+        let unsynced = self.unsynced;
+        match self.write_records(records, start) {
+            Ok(ranges) => Ok(ranges),
+            Err(e) => {
+                self.unsynced = unsynced;
+                if let Err(rollback) = self.writer.truncate(start) {
+                    error!(LOGGER, "Failed to roll back the log: {}", rollback);
+                    self.failed = true;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // writes the records at `start`, the end of the log, for `append`.
+    fn write_records(
+        &mut self,
+        records: &[(Vec<u8>, Vec<Range<u64>>)],
+        start: u64,
+    ) -> Result<Vec<Range<u64>>> {
+        let mut ranges = Vec::new();
+        for (record, nested) in records {
+            let pos = self.writer.pos;
+            self.writer.write_all(record)?;
+            ranges.extend(nested.iter().map(|r| pos + r.start..pos + r.end));
+        }
+        self.writer.flush()?;
+        self.unsynced += self.writer.pos - start;
+
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Bytes(bytes) if self.unsynced >= bytes => self.sync()?,
+            _ => {}
+        }
+        Ok(ranges)
+    }
+
+    /// Evicts the expired keys among the next `EXPIRY_SAMPLE_SIZE` keys of the
+    /// index after `cursor`, and moves the cursor past them.
+    ///
+    /// Returns how many keys were evicted. The cursor is reset once it reaches
+    /// the end of the index.
+    fn evict_expired(&mut self, cursor: &mut Option<Vec<u8>>) -> usize {
+        let entries: Vec<_> = match cursor {
+            Some(last) => self
+                .index
+                .range::<[u8], _>((Bound::Excluded(last.as_slice()), Bound::Unbounded))
+                .take(EXPIRY_SAMPLE_SIZE)
+                .collect(),
+            None => self.index.iter().take(EXPIRY_SAMPLE_SIZE).collect(),
+        };
+        *cursor = match entries.last() {
+            Some(entry) if entries.len() == EXPIRY_SAMPLE_SIZE => Some(entry.key().clone()),
+            _ => None,
+        };
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
+        let now = now_millis();
+        let oldest_view = self.views.state.lock().unwrap().oldest();
+        let mut evicted = 0;
+        for entry in entries {
+            let version = latest(entry.value());
+            if !is_unneeded(&version, oldest_view) {
+                continue;
+            }
+            match version.pos {
+                // the value is only stale once it is gone from the index, and
+                // its record is dropped by the next compaction.
+                Some(cmd_pos) if cmd_pos.is_expired(now) => {
+                    if entry.remove() {
+                        self.uncompacted += cmd_pos.len;
+                        evicted += 1;
+                    }
+                }
+                // a removed key that was kept for views which are closed now.
+                None => {
+                    entry.remove();
+                }
+                Some(_) => {}
+            }
+        }
+        evicted
+    }
+
+    /// Syncs the written data of the current log to the disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        // current_gen + 1 is for the compaction file, and current_gen + 2 for
+        // the writes after it.
+        let compaction_gen = self.current_gen + 1;
+        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+
+        // other writers are blocked on the writer lock, so only this thread
+        // changes the index. Readers keep using the old positions until all
+        // values are copied and the new positions are published below.
+        // only the newest version of each key is copied. Older versions that
+        // open views read stay in the stale log files, which are kept until
+        // those views are closed.
+        let oldest_view = self.views.state.lock().unwrap().oldest();
+        let mut new_pos = 0; // pos in the new log file.
+        let mut new_entries = Vec::with_capacity(self.index.len());
+        let mut dropped = Vec::new();
+        let now = now_millis();
+        for entry in self.index.iter() {
+            let version = latest(entry.value());
+            let cmd_pos = match version.pos {
+                Some(cmd_pos) if !cmd_pos.is_expired(now) => cmd_pos,
+                _ => {
+                    if is_unneeded(&version, oldest_view) {
+                        dropped.push(entry);
+                    }
+                    continue;
+                }
+            };
+            // records are decoded and encoded again rather than copied, so that
+            // checksums are verified and legacy JSON records are converted.
+            let record = record::encode(&self.reader.read_command(&cmd_pos)?)?;
+            compaction_writer.write_all(&record)?;
+            let len = record.len() as u64;
+            let new_cmd_pos =
+                CommandPos::new(compaction_gen, new_pos..new_pos + len, cmd_pos.expires_at);
+            new_entries.push((entry, new_cmd_pos));
             new_pos += len;
         }
+        // the stale generations must not be removed before the compaction file
+        // and its directory entry are on the disk.
         compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
+        // the next log is created only now, so that a crash during the
+        // compaction leaves the partial compaction file as the newest log, whose
+        // torn tail is dropped on open.
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        // values written to the previous log are either copied to the
+        // compaction file or stale.
+        self.unsynced = 0;
+        sync_dir(&self.path)?;
 
-        // remove stale log files.
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+        for (entry, cmd_pos) in new_entries {
+            let mut versions = entry.value().write().unwrap();
+            // moving a value does not modify it, so it keeps its sequence number.
+            versions.last_mut().unwrap().pos = Some(cmd_pos);
+            drop_unread_versions(&mut versions, oldest_view);
         }
+        // expired values were not copied, so their keys must be gone before
+        // their log files are.
+        for entry in dropped {
+            entry.remove();
+        }
+
+        // the index no longer refers to the stale log files for new readers.
+        self.views.retire(compaction_gen);
+        self.reader.close_stale_handles();
         self.uncompacted = 0;
 
         Ok(())
     }
+}
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
-    ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+/// Replaces the successful results of a group with copies of the error that
+/// failed it.
+fn fail_group<T>(results: Vec<Result<T>>, e: &KvsError) -> Vec<Result<T>> {
+    results
+        .into_iter()
+        .map(|result| {
+            result.and_then(|_| {
+                Err(match e {
+                    KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+                    e => KvsError::Io(io::Error::other(e.to_string())),
+                })
+            })
+        })
+        .collect()
+}
+
+/// Syncs the log from a background thread at the given interval.
+///
+/// The thread exits once every `KvStore` clone is dropped, or a write panicked.
+fn background_sync(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = match lock_writer(&writer) {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        if writer.unsynced > 0 {
+            if let Err(e) = writer.sync() {
+                error!(LOGGER, "Failed to sync the log: {}", e);
+            }
+        }
+    }
+}
+
+/// Evicts expired keys from a background thread at the given interval.
+///
+/// Each round checks a sample of keys and starts another round right away if
+/// more than a quarter of them had expired. The thread exits once every
+/// `KvStore` clone is dropped, or a write panicked.
+fn background_expire(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    let mut cursor = None;
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        loop {
+            let evicted = match lock_writer(&writer) {
+                Ok(mut writer) => writer.evict_expired(&mut cursor),
+                Err(_) => return,
+            };
+            if evicted * 4 <= EXPIRY_SAMPLE_SIZE {
+                break;
+            }
+        }
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Locks the writer, failing if a write panicked while holding it.
+fn lock_writer(writer: &Mutex<KvStoreWriter>) -> Result<MutexGuard<'_, KvStoreWriter>> {
+    writer.lock().map_err(|_| KvsError::WriterFailed)
+}
+
+/// Syncs the directory so that created and removed log files are on the disk.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened as files on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Create a new log file with given generation number.
 ///
 /// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
-    gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(path)?)?;
     Ok(writer)
 }
 
 /// Returns sorted generation numbers in the given directory.
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
@@ -243,38 +1282,335 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
+/// A record at the end of a log file that was not completely written.
+struct TornTail {
+    offset: u64,
+    reason: String,
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// Returns how many bytes can be saved after a compaction, and the torn record
+/// at the end of the file if there is one. Corruption anywhere else is an error.
 fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
+    index: &Index,
+) -> Result<(u64, Option<TornTail>)> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
     // To make sure we read from the beginning of the file.
+    reader.seek(SeekFrom::Start(0))?;
+    let mut first = [0; 1];
+    let legacy = reader.read(&mut first)? == 1 && first[0] == b'{';
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+    let now = now_millis();
+    let corruption = |offset, reason| KvsError::Corruption {
+        gen,
+        offset,
+        reason,
+    };
+    if legacy {
+        // generations written before the binary format are concatenated JSON.
+        let mut stream = Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+        while let Some(cmd) = stream.next() {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                Err(e) if e.is_eof() => {
+                    let reason = e.to_string();
+                    return Ok((
+                        uncompacted,
+                        Some(TornTail {
+                            offset: pos,
+                            reason,
+                        }),
+                    ));
                 }
-            }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                Err(e) => return Err(corruption(pos, e.to_string())),
+            };
+            let new_pos = stream.byte_offset() as u64;
+            uncompacted += apply(index, cmd.into(), gen, pos..new_pos, now);
+            pos = new_pos;
+        }
+    } else {
+        loop {
+            match record::read_record(reader) {
+                Ok(Some(record)) => {
+                    for (cmd, range) in record.cmds {
+                        let range = pos + range.start..pos + range.end;
+                        uncompacted += apply(index, cmd, gen, range, now);
+                    }
+                    pos += record.len;
+                }
+                Ok(None) => break,
+                Err(RecordError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    // a record that fails its checksum is only torn if nothing
+                    // follows it. A file extended before a crash ends in zeros.
+                    let torn = match e {
+                        RecordError::Truncated => true,
+                        RecordError::Checksum { len } if pos + len == file_len => true,
+                        _ => is_zeroed(reader, pos)?,
+                    };
+                    if !torn {
+                        return Err(corruption(pos, e.to_string()));
+                    }
+                    let reason = e.to_string();
+                    return Ok((
+                        uncompacted,
+                        Some(TornTail {
+                            offset: pos,
+                            reason,
+                        }),
+                    ));
                 }
-                // the "remove" command itself can be deleted in the next compaction.
-                // so we add its length to `uncompacted`.
-                uncompacted += new_pos - pos;
             }
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
+    Ok((uncompacted, None))
+}
+
+/// Returns whether the file read by `reader` holds only zeros from `pos` on.
+fn is_zeroed<R: Read + Seek>(reader: &mut R, pos: u64) -> Result<bool> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut buf = [0; 4096];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|&b| b != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Drops the torn record at the end of the log file of the given generation.
+fn truncate_torn_tail(path: &Path, gen: u64, torn: &TornTail) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(log_path(path, gen))?;
+    let dropped = file.metadata()?.len() - torn.offset;
+    file.set_len(torn.offset)?;
+    file.sync_all()?;
+    error!(
+        LOGGER,
+        "Dropped {} bytes of a torn record at offset {} of {}.log: {}",
+        dropped,
+        torn.offset,
+        gen,
+        torn.reason
+    );
+    Ok(())
+}
+
+/// Applies a command loaded from the log at `range` of generation `gen` to the index.
+///
+/// Returns how many bytes become stale.
+fn apply(index: &Index, cmd: Command, gen: u64, range: Range<u64>, now: u64) -> u64 {
+    let cmd_pos = CommandPos::new(gen, range, cmd.expires_at());
+    match cmd {
+        Command::Set { key, .. } if !cmd_pos.is_expired(now) => {
+            let version = Version {
+                seq: 0,
+                pos: Some(cmd_pos),
+            };
+            add_version(index, key, version, None).map_or(0, |old| old.len)
+        }
+        // a value that has expired is stale like a removed one.
+        Command::Set { key, .. } | Command::Remove { key } => {
+            let old_len = index
+                .remove(&key)
+                .and_then(|old| latest(old.value()).pos)
+                .map_or(0, |old| old.len);
+            // the "remove" command itself can be deleted in the next compaction.
+            old_len + cmd_pos.len
+        }
+    }
+}
+
+/// Decodes a single command read from the log, either a binary record or a
+/// legacy JSON one.
+fn decode_command(buf: &[u8]) -> std::result::Result<Command, String> {
+    if buf.first() == Some(&b'{') {
+        return serde_json::from_slice::<LegacyCommand>(buf)
+            .map(Command::from)
+            .map_err(|e| e.to_string());
+    }
+    match record::read_record(&mut &buf[..]) {
+        Ok(Some(mut record)) if record.cmds.len() == 1 => Ok(record.cmds.remove(0).0),
+        Ok(Some(_)) => Err("unexpected batch record".to_owned()),
+        Ok(None) => Err("empty record".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Adds a new version of a key, updating its existing entry in place, and
+/// drops the versions that no view open at `oldest_view` or later can read.
+///
+/// Returns the previous position of the key. Only one thread may update the index at a time.
+fn add_version(
+    index: &Index,
+    key: Vec<u8>,
+    version: Version,
+    oldest_view: Option<u64>,
+) -> Option<CommandPos> {
+    match index.get(&key) {
+        Some(entry) => {
+            let mut versions = entry.value().write().unwrap();
+            let old = versions.last().and_then(|old| old.pos.clone());
+            versions.push(version);
+            drop_unread_versions(&mut versions, oldest_view);
+            old
+        }
+        None => {
+            index.insert(key, RwLock::new(vec![version]));
+            None
+        }
+    }
+}
+
+/// Drops the versions older than the one the oldest open view reads, or all
+/// but the newest one if no view is open.
+fn drop_unread_versions(versions: &mut Vec<Version>, oldest_view: Option<u64>) {
+    let first_read = match oldest_view {
+        Some(seq) => versions
+            .iter()
+            .rposition(|version| version.seq <= seq)
+            .unwrap_or(0),
+        None => versions.len() - 1,
+    };
+    versions.drain(..first_read);
+}
+
+/// Returns the newest version of a key.
+fn latest(versions: &RwLock<Vec<Version>>) -> Version {
+    versions.read().unwrap().last().unwrap().clone()
+}
+
+/// Returns the version of a key seen by a view at sequence number `seq`.
+fn visible_at(versions: &[Version], seq: u64) -> Option<&Version> {
+    versions.iter().rev().find(|version| version.seq <= seq)
+}
+
+/// Returns whether no open view reads a version older than the newest one,
+/// which lets a removed or expired key leave the index.
+fn is_unneeded(latest: &Version, oldest_view: Option<u64>) -> bool {
+    oldest_view.is_none_or(|seq| seq >= latest.seq)
+}
+
+/// Removes the log files of the generations below `gen`.
+fn remove_stale_logs(path: &Path, gen: u64) {
+    let gen_list = match sorted_gen_list(path) {
+        Ok(gen_list) => gen_list,
+        Err(e) => {
+            error!(LOGGER, "Failed to list the log files: {}", e);
+            return;
+        }
+    };
+    for stale_gen in gen_list.into_iter().filter(|&stale_gen| stale_gen < gen) {
+        let file_path = log_path(path, stale_gen);
+        if let Err(e) = fs::remove_file(&file_path) {
+            error!(LOGGER, "{:?} cannot be deleted: {}", file_path, e);
+        }
+    }
+}
+
+/// The views open on a store, shared by all clones and the writer.
+///
+/// A view reads the store as of the last write applied to the index when it
+/// was opened. While it is open, the writer keeps the versions it reads in the
+/// index and compactions keep the log files they are in.
+struct Views {
+    state: Mutex<ViewState>,
+    path: Arc<PathBuf>,
+    // the safe point of the readers, moved when retired log files are removed.
+    safe_point: Arc<AtomicU64>,
+}
+
+struct ViewState {
+    // sequence number of the last write applied to the index. Values loaded
+    // from the log have sequence number 0.
+    last_seq: u64,
+    // the number of open views at each sequence number.
+    open: BTreeMap<u64, usize>,
+    // log files replaced by compactions but kept for open views, as the
+    // sequence number at the compaction and the generation below which files
+    // are stale. Views at or after that sequence number do not read them.
+    retired: Vec<(u64, u64)>,
+}
+
+impl ViewState {
+    /// Returns the sequence number of the oldest open view.
+    fn oldest(&self) -> Option<u64> {
+        self.open.keys().next().copied()
+    }
+}
+
+impl Views {
+    /// Marks the log files below `gen` as replaced by a compaction, and
+    /// removes them unless an older view is open.
+    fn retire(&self, gen: u64) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.last_seq;
+        state.retired.push((seq, gen));
+        self.remove_retired(&mut state);
+    }
+
+    /// Removes the retired log files that no open view reads any more.
+    fn remove_retired(&self, state: &mut ViewState) {
+        let oldest_view = state.oldest();
+        let gen = match state
+            .retired
+            .iter()
+            .filter(|&&(seq, _)| oldest_view.is_none_or(|oldest| oldest >= seq))
+            .map(|&(_, gen)| gen)
+            .max()
+        {
+            Some(gen) => gen,
+            None => return,
+        };
+        state.retired.retain(|&(_, retired)| retired > gen);
+        // readers that still hold a position in the removed files see the new
+        // safe point and retry the lookup.
+        self.safe_point.store(gen, Ordering::SeqCst);
+        remove_stale_logs(&self.path, gen);
+    }
+}
+
+/// An open view of a store, closed when dropped.
+pub(crate) struct ViewPin {
+    views: Arc<Views>,
+    seq: u64,
+}
+
+impl ViewPin {
+    /// Returns the sequence number of the last write the view sees.
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Clone for ViewPin {
+    /// Opens another view at the same sequence number.
+    fn clone(&self) -> ViewPin {
+        let mut state = self.views.state.lock().unwrap();
+        *state.open.entry(self.seq).or_insert(0) += 1;
+        ViewPin {
+            views: Arc::clone(&self.views),
+            seq: self.seq,
+        }
+    }
+}
+
+impl Drop for ViewPin {
+    fn drop(&mut self) {
+        let mut state = self.views.state.lock().unwrap();
+        if let Entry::Occupied(mut entry) = state.open.entry(self.seq) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        self.views.remove_retired(&mut state);
+    }
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
@@ -282,37 +1618,170 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
 }
 
 /// Struct representing a command.
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
+#[derive(Debug)]
+pub(crate) enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // absolute expiry in milliseconds since the Unix epoch.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+/// A command of a log written as concatenated JSON, before the binary format.
+///
+/// Such logs only hold string keys and values, and no expiries.
+#[derive(Deserialize)]
+enum LegacyCommand {
     Set { key: String, value: String },
     Remove { key: String },
 }
 
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            LegacyCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
+}
+
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    pub(crate) fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        }
     }
 
-    fn remove(key: String) -> Command {
+    pub(crate) fn set_expiring(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            Command::Remove { .. } => None,
+        }
+    }
+
+    pub(crate) fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log.
+/// A write waiting in the group commit queue.
+struct LogWrite {
+    cmds: Vec<Command>,
+    // keys read by a transaction, with the sequence numbers it saw.
+    reads: Vec<(Vec<u8>, Option<u64>)>,
+    // for each command, whether it is a remove that is skipped rather than
+    // failing the write when the key is missing.
+    optional: Vec<bool>,
+}
+
+impl LogWrite {
+    fn new(cmds: Vec<Command>) -> LogWrite {
+        let optional = vec![false; cmds.len()];
+        LogWrite {
+            cmds,
+            reads: Vec::new(),
+            optional,
+        }
+    }
+
+    /// Converts the operations of a batch to the commands written to the log.
+    fn from_batch(batch: WriteBatch) -> LogWrite {
+        let (cmds, optional) = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => (Command::set(key, value), false),
+                BatchOp::Remove { key } => (Command::remove(key), false),
+                BatchOp::Delete { key } => (Command::remove(key), true),
+            })
+            .unzip();
+        LogWrite {
+            cmds,
+            reads: Vec::new(),
+            optional,
+        }
+    }
+}
+
+/// Adds `delta` to an integer value stored as a decimal string, `None`
+/// counting as 0.
+pub(crate) fn incr(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let value = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    value.checked_add(delta).ok_or(KvsError::NotAnInteger)
+}
+
+/// A value of a key as written by the write with sequence number `seq`.
+#[derive(Clone)]
+struct Version {
+    seq: u64,
+    // position of the value in the log, `None` if the write removed the key.
+    pos: Option<CommandPos>,
+}
+
+/// Represents the position and length of a command record in the log, and
+/// when the value expires.
+#[derive(Clone)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    expires_at: Option<u64>,
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
+impl CommandPos {
+    fn new(gen: u64, range: Range<u64>, expires_at: Option<u64>) -> CommandPos {
         CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at,
         }
     }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A value read together with the index entry it was read from.
+pub(crate) struct Versioned {
+    /// The value, `None` if the key is missing or has expired.
+    pub(crate) value: Option<Vec<u8>>,
+    /// When the value expires.
+    pub(crate) expires_at: Option<u64>,
+    /// Sequence number of the index entry, `None` if there is no entry.
+    pub(crate) seq: Option<u64>,
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the absolute expiry of a value that expires after `ttl`.
+pub(crate) fn expiry(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
 }
 
 struct BufReaderWithPos<R: Read + Seek> {
@@ -322,7 +1791,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -352,7 +1821,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -360,6 +1829,18 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    /// Drops the buffered bytes and cuts the file back to `pos`.
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        file.set_len(pos)?;
+        // the old buffer is dropped without being flushed.
+        let (_, _buffered) = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        self.pos = self.writer.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -378,3 +1859,73 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A write that panics while holding the writer should fail later writes
+    // instead of panicking them, and leave reads working.
+    #[test]
+    fn writer_panicked() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(10)),
+            expiry_sample_interval: Duration::from_millis(10),
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let writer = Arc::clone(&store.writer);
+        let panicked = thread::spawn(move || {
+            let _writer = writer.lock().unwrap();
+            panic!("write failed");
+        })
+        .join();
+        assert!(panicked.is_err());
+        // the background threads see the poisoned writer.
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(matches!(
+            store.set("key2".to_owned(), "value2".to_owned()),
+            Err(KvsError::WriterFailed)
+        ));
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvsError::WriterFailed)
+        ));
+        assert!(matches!(store.compact(), Err(KvsError::WriterFailed)));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        Ok(())
+    }
+
+    // Rolling back a failed append should drop both the flushed and the
+    // buffered bytes of its records.
+    #[test]
+    fn truncate_log_writer() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut writer = new_log_file(temp_dir.path(), 1)?;
+        writer.write_all(b"good")?;
+        writer.flush()?;
+        writer.write_all(b"flushed")?;
+        writer.flush()?;
+        writer.write_all(b"buffered")?;
+
+        writer.truncate(4)?;
+        assert_eq!(writer.pos, 4);
+        writer.write_all(b"next")?;
+        writer.flush()?;
+        drop(writer);
+        assert_eq!(fs::read(log_path(temp_dir.path(), 1))?, b"goodnext");
+
+        Ok(())
+    }
+}
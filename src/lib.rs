@@ -1,17 +1,35 @@
 // #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use batch::{BatchOp, WriteBatch};
 pub use command::ClientCommand;
-pub use engine::KvsEngine;
+pub use engine::{KvsEngine, Scan, ScanBytes};
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use iterator::KvIterator;
+pub use kv::{KvStore, KvStoreOptions, SyncPolicy};
 pub use logger::{init_logger, LOGGER};
+pub use protocol::{read_frame, write_frame, Request, Response, FRAME_MAGIC, PROTOCOL_VERSION};
+pub use resp::{RespDecoder, RespValue};
+#[cfg(feature = "sled")]
+pub use sled_engine::SledKvsEngine;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
 pub use util::*;
 
+mod batch;
 mod command;
 mod engine;
 mod error;
+mod group_commit;
+mod iterator;
 mod kv;
 mod logger;
+mod protocol;
+mod record;
 mod resp;
+#[cfg(feature = "sled")]
+mod sled_engine;
+mod snapshot;
+pub mod thread_pool;
+mod transaction;
 mod util;
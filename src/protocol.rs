@@ -0,0 +1,127 @@
+use crate::{ClientCommand, KvsError, Result, WriteBatch};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// 每个数据帧的第一个字节，用于和其他协议的数据区分
+pub const FRAME_MAGIC: u8 = 0xCB;
+
+/// 当前使用的协议版本
+///
+/// 版本 2 中 key 和 value 由字符串改为字节数组，版本 3 中数据帧的内容由 JSON
+/// 改为 bincode，字节数组不再被编码成数字列表。
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// 单个数据帧允许的最大长度
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// magic(1) + version(1) + payload length(4)
+const HEADER_LEN: usize = 6;
+
+/// 客户端发送给服务器的请求
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    // 执行一条指令
+    Command(ClientCommand),
+    // 原子地执行一组写操作
+    Batch(WriteBatch),
+}
+
+/// 服务器对一条请求的执行结果
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    // 执行成功，没有返回值
+    Success,
+    // 执行成功，返回 key 对应的值
+    Value(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    // 执行成功，返回一个整数
+    Integer(i64),
+    // 执行成功，返回一页键值对以及下一页的游标，游标为 "0" 表示扫描结束
+    Scan {
+        cursor: String,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    // 执行失败，携带错误信息
+    Error(String),
+}
+
+/// 将一个值编码成数据帧并写入 `writer`
+///
+/// 数据帧的格式为 `magic | version | length (u32, big endian) | bincode payload`。
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = payload_options()
+        .serialize(value)
+        .map_err(|e| KvsError::Protocol(format!("invalid frame payload: {}", e)))?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            payload.len(),
+            MAX_FRAME_LEN
+        )));
+    }
+
+    let mut header = [0; HEADER_LEN];
+    header[0] = FRAME_MAGIC;
+    header[1] = PROTOCOL_VERSION;
+    header[2..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// 从 `reader` 中读取一个完整的数据帧并解码
+///
+/// 如果对端在数据帧开始之前关闭了连接，返回 `None`。
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut header = [0; HEADER_LEN];
+    if !read_header(reader, &mut header)? {
+        return Ok(None);
+    }
+    if header[0] != FRAME_MAGIC {
+        return Err(KvsError::Protocol(format!(
+            "invalid frame magic {:#04x}",
+            header[0]
+        )));
+    }
+    if header[1] != PROTOCOL_VERSION {
+        return Err(KvsError::Protocol(format!(
+            "unsupported protocol version {}",
+            header[1]
+        )));
+    }
+
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            len, MAX_FRAME_LEN
+        )));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    let value = payload_options()
+        .deserialize(&payload)
+        .map_err(|e| KvsError::Protocol(format!("invalid frame payload: {}", e)))?;
+    Ok(Some(value))
+}
+
+/// 数据帧内容的编码方式，长度限制使得解码时不会按照不可信的长度分配过大的内存
+fn payload_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(u64::from(MAX_FRAME_LEN))
+}
+
+/// 读取帧头，在读到任何数据之前遇到 EOF 时返回 `false`
+fn read_header<R: Read>(reader: &mut R, header: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
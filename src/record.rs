@@ -0,0 +1,308 @@
+//! Binary format of the records in a log file.
+//!
+//! Each record is a fixed-size header followed by the key and the value:
+//!
+//! ```text
+//! +-------+------+---------+-----------+------------+-------+-----+-------+
+//! | magic | type | key_len | value_len | header_crc | crc32 | key | value |
+//! |  u8   |  u8  | u32 BE  |  u32 BE   |   u32 BE   | u32BE |     |       |
+//! +-------+------+---------+-----------+------------+-------+-----+-------+
+//! ```
+//!
+//! The CRC32 covers the type, both lengths, the key and the value, so a flipped
+//! bit anywhere in the record other than the magic byte is detected. The header
+//! CRC32 covers only the type and both lengths, so the lengths can be trusted
+//! before the rest of the record is read: a record that ends past the end of
+//! the log was cut off, rather than given a corrupted length.
+//!
+//! A value with an expiry is written as a set-expiring record, whose value is
+//! the absolute expiry in milliseconds since the Unix epoch as a `u64 BE`,
+//! followed by the value itself.
+//!
+//! Commands written by one `KvsEngine::write` are framed as a single batch
+//! record, whose value is the standalone records of the commands. A batch is
+//! covered by one checksum, so it is either loaded as a whole or not at all,
+//! while the index can still point at each nested record on its own.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::Range;
+
+use crate::kv::Command;
+use crate::{KvsError, Result};
+
+/// First byte of every binary record.
+pub(crate) const RECORD_MAGIC: u8 = 0xB7;
+
+const HEADER_LEN: usize = 18;
+
+const RECORD_SET: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+const RECORD_BATCH: u8 = 3;
+const RECORD_SET_EXPIRING: u8 = 4;
+
+// length of the expiry prefixed to the value of a set-expiring record.
+const EXPIRY_LEN: usize = 8;
+
+/// Reasons a record cannot be read.
+pub(crate) enum RecordError {
+    /// The log ends in the middle of a record whose header, if complete, is valid.
+    Truncated,
+    /// The record is complete but its checksum does not match.
+    Checksum {
+        /// Length of the whole record, as declared in its header.
+        len: u64,
+    },
+    /// The record is complete but invalid.
+    Corrupted(String),
+    /// The underlying reader failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Truncated => write!(f, "truncated record"),
+            RecordError::Checksum { .. } => write!(f, "checksum mismatch"),
+            RecordError::Corrupted(reason) => write!(f, "{}", reason),
+            RecordError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Encodes a command as a binary record.
+///
+/// It returns `KvsError::RecordTooLarge` if the key or the value does not fit
+/// in the length fields of the header.
+pub(crate) fn encode(cmd: &Command) -> Result<Vec<u8>> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        } => frame(RECORD_SET, key, value),
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            let mut payload = Vec::with_capacity(EXPIRY_LEN + value.len());
+            payload.extend_from_slice(&expires_at.to_be_bytes());
+            payload.extend_from_slice(value);
+            frame(RECORD_SET_EXPIRING, key, &payload)
+        }
+        Command::Remove { key } => frame(RECORD_REMOVE, key, &[]),
+    }
+}
+
+/// Encodes the commands of one write: a single command as its own record, and
+/// several commands that must be applied together as a batch record.
+///
+/// Returns the record and the range of each command's record, relative to the
+/// start of the returned record. Nothing is encoded for a write without
+/// commands.
+pub(crate) fn encode_write(cmds: &[Command]) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    match cmds {
+        [] => Ok((Vec::new(), Vec::new())),
+        [cmd] => {
+            let record = encode(cmd)?;
+            let range = 0..record.len() as u64;
+            Ok((record, vec![range]))
+        }
+        _ => encode_batch(cmds),
+    }
+}
+
+/// Encodes commands that must be applied together as a batch record.
+///
+/// Returns the record and the range of each nested record, relative to the
+/// start of the batch record.
+fn encode_batch(cmds: &[Command]) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let mut payload = Vec::new();
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = (HEADER_LEN + payload.len()) as u64;
+        payload.extend_from_slice(&encode(cmd)?);
+        ranges.push(start..(HEADER_LEN + payload.len()) as u64);
+    }
+    Ok((frame(RECORD_BATCH, &[], &payload)?, ranges))
+}
+
+fn frame(record_type: u8, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    // a length that does not fit in the header would be truncated silently.
+    let field = |bytes: &[u8]| {
+        u32::try_from(bytes.len()).map_err(|_| KvsError::RecordTooLarge(bytes.len() as u64))
+    };
+    let key_len = field(key)?;
+    let value_len = field(value)?;
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    buf.push(RECORD_MAGIC);
+    buf.push(record_type);
+    buf.extend_from_slice(&key_len.to_be_bytes());
+    buf.extend_from_slice(&value_len.to_be_bytes());
+    let header_crc = checksum(&buf[1..10], &[], &[]);
+    let crc = checksum(&buf[1..10], key, value);
+    buf.extend_from_slice(&header_crc.to_be_bytes());
+    buf.extend_from_slice(&crc.to_be_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    Ok(buf)
+}
+
+/// The commands of a record read from the log.
+pub(crate) struct Record {
+    /// Each command with the range of its record, relative to the start of
+    /// this record.
+    pub(crate) cmds: Vec<(Command, Range<u64>)>,
+    /// Length of the whole record.
+    pub(crate) len: u64,
+}
+
+/// A record before its payload is interpreted.
+struct Frame {
+    record_type: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    len: u64,
+}
+
+/// Reads the next record from `reader`.
+///
+/// Returns `None` if the reader is at the end before the first byte of a record.
+pub(crate) fn read_record<R: Read>(
+    reader: &mut R,
+) -> std::result::Result<Option<Record>, RecordError> {
+    let frame = match read_frame(reader)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    let len = frame.len;
+    if frame.record_type != RECORD_BATCH {
+        let cmd = to_command(frame)?;
+        return Ok(Some(Record {
+            cmds: vec![(cmd, 0..len)],
+            len,
+        }));
+    }
+
+    // the checksum of the batch covers the nested records, so they are complete.
+    let mut cmds = Vec::new();
+    let mut nested = &frame.value[..];
+    let mut pos = HEADER_LEN as u64;
+    while !nested.is_empty() {
+        let nested_frame = match read_frame(&mut nested) {
+            Ok(Some(nested_frame)) => nested_frame,
+            _ => return Err(RecordError::Corrupted("invalid record in batch".to_owned())),
+        };
+        if nested_frame.record_type == RECORD_BATCH {
+            return Err(RecordError::Corrupted("nested batch record".to_owned()));
+        }
+        let nested_len = nested_frame.len;
+        cmds.push((to_command(nested_frame)?, pos..pos + nested_len));
+        pos += nested_len;
+    }
+    Ok(Some(Record { cmds, len }))
+}
+
+/// Reads the header and payload of the next record and verifies its checksum.
+fn read_frame<R: Read>(reader: &mut R) -> std::result::Result<Option<Frame>, RecordError> {
+    let mut header = [0; HEADER_LEN];
+    match fill(reader, &mut header)? {
+        0 => return Ok(None),
+        HEADER_LEN => {}
+        _ => return Err(RecordError::Truncated),
+    }
+
+    if header[0] != RECORD_MAGIC {
+        return Err(RecordError::Corrupted(format!(
+            "invalid record magic 0x{:02x}",
+            header[0]
+        )));
+    }
+    let header_crc = u32::from_be_bytes([header[10], header[11], header[12], header[13]]);
+    if checksum(&header[1..10], &[], &[]) != header_crc {
+        return Err(RecordError::Corrupted(
+            "header checksum mismatch".to_owned(),
+        ));
+    }
+    let key_len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as u64;
+    let value_len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]) as u64;
+    let crc = u32::from_be_bytes([header[14], header[15], header[16], header[17]]);
+
+    // the lengths may still exceed the log, so the payload is not preallocated.
+    let mut payload = Vec::new();
+    reader
+        .take(key_len + value_len)
+        .read_to_end(&mut payload)
+        .map_err(RecordError::Io)?;
+    if (payload.len() as u64) < key_len + value_len {
+        return Err(RecordError::Truncated);
+    }
+
+    let (key_bytes, value_bytes) = payload.split_at(key_len as usize);
+    if checksum(&header[1..10], key_bytes, value_bytes) != crc {
+        return Err(RecordError::Checksum {
+            len: HEADER_LEN as u64 + key_len + value_len,
+        });
+    }
+
+    let value = payload.split_off(key_len as usize);
+    Ok(Some(Frame {
+        record_type: header[1],
+        key: payload,
+        value,
+        len: HEADER_LEN as u64 + key_len + value_len,
+    }))
+}
+
+/// Builds the command of a set or remove record.
+///
+/// Keys and values are arbitrary bytes.
+fn to_command(frame: Frame) -> std::result::Result<Command, RecordError> {
+    let key = frame.key;
+    match frame.record_type {
+        RECORD_SET => Ok(Command::Set {
+            key,
+            value: frame.value,
+            expires_at: None,
+        }),
+        RECORD_SET_EXPIRING if frame.value.len() >= EXPIRY_LEN => {
+            let mut expiry = [0; EXPIRY_LEN];
+            expiry.copy_from_slice(&frame.value[..EXPIRY_LEN]);
+            Ok(Command::Set {
+                key,
+                value: frame.value[EXPIRY_LEN..].to_vec(),
+                expires_at: Some(u64::from_be_bytes(expiry)),
+            })
+        }
+        RECORD_SET_EXPIRING => Err(RecordError::Corrupted("missing expiry".to_owned())),
+        RECORD_REMOVE => Ok(Command::Remove { key }),
+        t => Err(RecordError::Corrupted(format!("unknown record type {}", t))),
+    }
+}
+
+/// Computes the CRC32 of a record from its type and lengths, key and value.
+fn checksum(header: &[u8], key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+/// Reads into `buf` until it is full or the reader reaches the end.
+///
+/// Returns the number of bytes read.
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::result::Result<usize, RecordError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(RecordError::Io(e)),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,330 @@
+use super::{inline, RespValue};
+use crate::{KvsError, Result};
+use std::str;
+
+/// 默认允许的最大 bulk string 长度，与 Redis 的 `proto-max-bulk-len` 一致
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// 默认允许的最大数组嵌套层数
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+// 单行（类型标记、长度、simple string 等）允许的最大长度
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// 聚合类型允许的最大元素个数，与 Redis 对 multibulk 长度的限制一致
+const MAX_AGGREGATE_LEN: i64 = i32::MAX as i64;
+
+/// 增量式的 RESP 解码器
+///
+/// 从 TCP 连接中读到的数据可以按任意大小的块交给 `feed`，
+/// 然后反复调用 `decode_next` 取出已经完整到达的值。
+///
+/// ```rust
+/// # use kvs::{RespDecoder, RespValue, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut decoder = RespDecoder::new();
+/// decoder.feed(b"*1\r\n$4\r\nPI");
+/// assert_eq!(decoder.decode_next()?, None);
+/// decoder.feed(b"NG\r\n");
+/// let ping = RespValue::BulkStrings(Some(b"PING".to_vec()));
+/// assert_eq!(decoder.decode_next()?, Some(RespValue::Array(vec![ping])));
+/// # Ok(())
+/// # }
+/// ```
+pub struct RespDecoder {
+    // 已经收到但还没有被解码的数据
+    buf: Vec<u8>,
+    // 缓冲区至少达到这个长度时才有可能解码出下一个值
+    needed: usize,
+    max_bulk_len: usize,
+    max_depth: usize,
+}
+
+/// 一次解码尝试的结果
+enum Parsed {
+    // 数据不完整，缓冲区至少需要达到给定的长度
+    Incomplete(usize),
+    // 解码出一个值，以及该值结束的位置
+    Complete(RespValue, usize),
+}
+
+impl Default for RespDecoder {
+    fn default() -> Self {
+        RespDecoder::new()
+    }
+}
+
+impl RespDecoder {
+    /// 使用默认的长度和嵌套限制创建解码器
+    pub fn new() -> RespDecoder {
+        RespDecoder::with_limits(DEFAULT_MAX_BULK_LEN, DEFAULT_MAX_DEPTH)
+    }
+
+    /// 使用给定的 bulk string 最大长度和数组最大嵌套层数创建解码器
+    pub fn with_limits(max_bulk_len: usize, max_depth: usize) -> RespDecoder {
+        RespDecoder {
+            buf: Vec::new(),
+            needed: 0,
+            max_bulk_len,
+            max_depth,
+        }
+    }
+
+    /// 追加从连接中读到的数据
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// 已经缓冲但还没有被解码的字节数
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// 从缓冲区中取出下一个完整的值
+    ///
+    /// 数据还不完整时返回 `None`，需要继续 `feed`。
+    ///
+    /// # Errors
+    ///
+    /// 数据格式错误或超出限制时返回 `KvsError::Protocol`，此后缓冲区中的数据不再可信。
+    pub fn decode_next(&mut self) -> Result<Option<RespValue>> {
+        if self.buf.is_empty() || self.buf.len() < self.needed {
+            return Ok(None);
+        }
+        match self.parse(&self.buf, 0, 0)? {
+            Parsed::Complete(value, consumed) => {
+                self.buf.drain(..consumed);
+                self.needed = 0;
+                Ok(Some(value))
+            }
+            Parsed::Incomplete(needed) => {
+                self.needed = needed;
+                Ok(None)
+            }
+        }
+    }
+
+    /// 从缓冲区中取出下一条客户端请求
+    ///
+    /// 除了 RESP 数组之外，还接受 `SET foo bar` 这样以换行结尾的内联指令，
+    /// 内联指令会被转换为由 bulk string 组成的数组。空行会被忽略。
+    pub fn decode_request(&mut self) -> Result<Option<RespValue>> {
+        loop {
+            match self.buf.first() {
+                None => return Ok(None),
+                Some(b'*') => return self.decode_next(),
+                Some(_) => {}
+            }
+
+            let end = match self.buf.iter().position(|&c| c == b'\n') {
+                Some(end) if end > MAX_LINE_LEN => {
+                    return Err(protocol_error("too big inline request"))
+                }
+                Some(end) => end,
+                None if self.buf.len() > MAX_LINE_LEN => {
+                    return Err(protocol_error("too big inline request"))
+                }
+                None => return Ok(None),
+            };
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let args = inline::split_args(line)?;
+            if !args.is_empty() {
+                let args = args
+                    .into_iter()
+                    .map(|arg| RespValue::BulkStrings(Some(arg)));
+                return Ok(Some(RespValue::Array(args.collect())));
+            }
+        }
+    }
+
+    /// 尝试从 `buf` 的开头解码一个值，不修改解码器内部的缓冲区
+    ///
+    /// 成功时返回解码出的值以及消耗的字节数，数据不完整时返回 `None`。
+    pub fn decode(&self, buf: &[u8]) -> Result<Option<(RespValue, usize)>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        match self.parse(buf, 0, 0)? {
+            Parsed::Complete(value, consumed) => Ok(Some((value, consumed))),
+            Parsed::Incomplete(_) => Ok(None),
+        }
+    }
+
+    fn parse(&self, buf: &[u8], pos: usize, depth: usize) -> Result<Parsed> {
+        let (line, next) = match read_line(buf, pos)? {
+            Some(line) => line,
+            None => return Ok(Parsed::Incomplete(buf.len() + 1)),
+        };
+        let (kind, content) = match line.split_first() {
+            Some(split) => split,
+            None => return Err(protocol_error("missing type byte")),
+        };
+
+        let value = match kind {
+            b'+' => RespValue::SimpleStrings(parse_str(content)?.to_owned()),
+            b'-' => RespValue::Error(parse_str(content)?.to_owned()),
+            b':' => RespValue::Integer(parse_int(content)?),
+            b'_' if content.is_empty() => RespValue::Null,
+            b'#' => match content {
+                b"t" => RespValue::Boolean(true),
+                b"f" => RespValue::Boolean(false),
+                _ => return Err(protocol_error("invalid boolean")),
+            },
+            b',' => RespValue::Double(parse_double(content)?),
+            b'(' => RespValue::BigNumber(parse_big_number(content)?),
+            b'$' | b'=' => {
+                let len = parse_int(content)?;
+                // RESP2 中使用长度 -1 表示 null bulk string
+                if len == -1 && *kind == b'$' {
+                    return Ok(Parsed::Complete(RespValue::BulkStrings(None), next));
+                }
+                let (data, end) = match self.parse_blob(buf, next, len)? {
+                    Some(blob) => blob,
+                    None => return Ok(Parsed::Incomplete(next + len as usize + 2)),
+                };
+                let value = if *kind == b'$' {
+                    RespValue::BulkStrings(Some(data.to_vec()))
+                } else {
+                    // verbatim string 的内容以三个字节的格式说明和冒号开头，例如 `txt:`
+                    if data.len() < 4 || data[3] != b':' {
+                        return Err(protocol_error("invalid verbatim string"));
+                    }
+                    RespValue::VerbatimString {
+                        format: parse_str(&data[..3])?.to_owned(),
+                        data: data[4..].to_vec(),
+                    }
+                };
+                return Ok(Parsed::Complete(value, end));
+            }
+            b'*' | b'~' | b'>' | b'%' => {
+                let len = parse_int(content)?;
+                // RESP2 中的 null array 与 null bulk string 含义相同
+                if len == -1 && *kind == b'*' {
+                    return Ok(Parsed::Complete(RespValue::BulkStrings(None), next));
+                }
+                if !(0..=MAX_AGGREGATE_LEN).contains(&len) {
+                    return Err(protocol_error("invalid aggregate length"));
+                }
+                if depth + 1 > self.max_depth {
+                    return Err(protocol_error(&format!(
+                        "aggregates nested deeper than {} levels",
+                        self.max_depth
+                    )));
+                }
+                // map 中的每一项由 key 和 value 两个元素组成
+                let count = if *kind == b'%' { len * 2 } else { len };
+                // 长度来自对端，不能直接用于预分配内存
+                let mut elements = Vec::with_capacity((count as usize).min(1024));
+                let mut pos = next;
+                for _ in 0..count {
+                    match self.parse(buf, pos, depth + 1)? {
+                        Parsed::Complete(value, end) => {
+                            elements.push(value);
+                            pos = end;
+                        }
+                        incomplete => return Ok(incomplete),
+                    }
+                }
+                let value = match kind {
+                    b'*' => RespValue::Array(elements),
+                    b'~' => RespValue::Set(elements),
+                    b'>' => RespValue::Push(elements),
+                    _ => {
+                        let mut elements = elements.into_iter();
+                        let mut entries = Vec::with_capacity(elements.len() / 2);
+                        while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                            entries.push((key, value));
+                        }
+                        RespValue::Map(entries)
+                    }
+                };
+                return Ok(Parsed::Complete(value, pos));
+            }
+            _ => {
+                return Err(protocol_error(&format!(
+                    "unexpected type byte '{}'",
+                    char::from(*kind).escape_default()
+                )))
+            }
+        };
+        Ok(Parsed::Complete(value, next))
+    }
+
+    /// 读取从 `pos` 开始、长度为 `len` 并以 CRLF 结尾的二进制数据
+    ///
+    /// 返回数据以及其结束的位置，数据还不完整时返回 `None`。
+    fn parse_blob<'a>(
+        &self,
+        buf: &'a [u8],
+        pos: usize,
+        len: i64,
+    ) -> Result<Option<(&'a [u8], usize)>> {
+        if len < 0 {
+            return Err(protocol_error("invalid bulk string length"));
+        }
+        let len = len as usize;
+        if len > self.max_bulk_len {
+            return Err(protocol_error(&format!(
+                "bulk string of {} bytes exceeds the limit of {} bytes",
+                len, self.max_bulk_len
+            )));
+        }
+        let end = pos + len + 2;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        if &buf[end - 2..end] != b"\r\n" {
+            return Err(protocol_error("bulk string not terminated with CRLF"));
+        }
+        Ok(Some((&buf[pos..end - 2], end)))
+    }
+}
+
+/// 读取从 `pos` 开始、以 CRLF 结尾的一行，返回行的内容（不含 CRLF）以及下一行开始的位置
+fn read_line(buf: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>> {
+    let rest = &buf[pos..];
+    match rest.windows(2).position(|w| w == b"\r\n") {
+        Some(i) if i > MAX_LINE_LEN => Err(protocol_error("line too long")),
+        Some(i) => Ok(Some((&rest[..i], pos + i + 2))),
+        None if rest.len() > MAX_LINE_LEN => Err(protocol_error("line too long")),
+        None => Ok(None),
+    }
+}
+
+fn parse_str(content: &[u8]) -> Result<&str> {
+    str::from_utf8(content).map_err(|_| protocol_error("invalid UTF-8 string"))
+}
+
+fn parse_int(content: &[u8]) -> Result<i64> {
+    parse_str(content)?
+        .parse()
+        .map_err(|_| protocol_error("invalid integer"))
+}
+
+fn parse_double(content: &[u8]) -> Result<f64> {
+    match content {
+        b"inf" => Ok(f64::INFINITY),
+        b"-inf" => Ok(f64::NEG_INFINITY),
+        b"nan" => Ok(f64::NAN),
+        _ => parse_str(content)?
+            .parse()
+            .map_err(|_| protocol_error("invalid double")),
+    }
+}
+
+fn parse_big_number(content: &[u8]) -> Result<String> {
+    let digits = match content.first() {
+        Some(b'-') | Some(b'+') => &content[1..],
+        _ => content,
+    };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(protocol_error("invalid big number"));
+    }
+    Ok(parse_str(content)?.to_owned())
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
@@ -0,0 +1,90 @@
+use super::{format_double, RespValue};
+use std::io::{self, Write};
+
+impl RespValue {
+    /// 将值按照 RESP 格式编码并写入 `writer`
+    ///
+    /// RESP3 独有的类型会按照 RESP3 的格式编码，
+    /// 需要发送给 RESP2 客户端时先调用 `into_resp2` 进行转换。
+    /// 简单字符串和错误不能包含换行，其中的 CR 和 LF 会被替换为空格。
+    ///
+    /// ```rust
+    /// # use kvs::RespValue;
+    /// let value = RespValue::Array(vec![
+    ///     RespValue::BulkStrings(Some(b"GET".to_vec())),
+    ///     RespValue::BulkStrings(None),
+    /// ]);
+    /// assert_eq!(value.to_bytes(), b"*2\r\n$3\r\nGET\r\n$-1\r\n");
+    /// ```
+    pub fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            RespValue::SimpleStrings(s) => write_simple(writer, b'+', s),
+            RespValue::Error(e) => write_simple(writer, b'-', e),
+            RespValue::Integer(i) => write_line(writer, b':', i.to_string().as_bytes()),
+            RespValue::BulkStrings(Some(data)) => write_blob(writer, b'$', &[data]),
+            RespValue::BulkStrings(None) => writer.write_all(b"$-1\r\n"),
+            RespValue::Array(values) => write_aggregate(writer, b'*', values),
+            RespValue::Null => writer.write_all(b"_\r\n"),
+            RespValue::Boolean(b) => write_line(writer, b'#', if *b { b"t" } else { b"f" }),
+            RespValue::Double(d) => write_line(writer, b',', format_double(*d).as_bytes()),
+            RespValue::BigNumber(n) => write_line(writer, b'(', n.as_bytes()),
+            RespValue::VerbatimString { format, data } => {
+                write_blob(writer, b'=', &[format.as_bytes(), b":", data])
+            }
+            RespValue::Map(entries) => {
+                write_line(writer, b'%', entries.len().to_string().as_bytes())?;
+                for (key, value) in entries {
+                    key.encode(writer)?;
+                    value.encode(writer)?;
+                }
+                Ok(())
+            }
+            RespValue::Set(values) => write_aggregate(writer, b'~', values),
+            RespValue::Push(values) => write_aggregate(writer, b'>', values),
+        }
+    }
+
+    /// 将值按照 RESP 格式编码为字节数组
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf)
+            .expect("writing to a Vec<u8> never fails");
+        buf
+    }
+}
+
+/// 写入一个类型标记、内容以及结尾的 CRLF
+fn write_line<W: Write>(writer: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    writer.write_all(&[kind])?;
+    writer.write_all(content)?;
+    writer.write_all(b"\r\n")
+}
+
+/// 写入一个不带长度前缀的字符串，其中的 CR 和 LF 会被替换为空格，
+/// 否则对端会把它们之后的内容当作下一个值
+fn write_simple<W: Write>(writer: &mut W, kind: u8, s: &str) -> io::Result<()> {
+    if !s.contains(['\r', '\n']) {
+        return write_line(writer, kind, s.as_bytes());
+    }
+    let line = s.replace(['\r', '\n'], " ");
+    write_line(writer, kind, line.as_bytes())
+}
+
+/// 写入一个带长度前缀的二进制安全的值，内容由 `parts` 依次拼接而成
+fn write_blob<W: Write>(writer: &mut W, kind: u8, parts: &[&[u8]]) -> io::Result<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    write_line(writer, kind, len.to_string().as_bytes())?;
+    for part in parts {
+        writer.write_all(part)?;
+    }
+    writer.write_all(b"\r\n")
+}
+
+/// 写入一个由若干元素组成的聚合类型
+fn write_aggregate<W: Write>(writer: &mut W, kind: u8, values: &[RespValue]) -> io::Result<()> {
+    write_line(writer, kind, values.len().to_string().as_bytes())?;
+    for value in values {
+        value.encode(writer)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use crate::{KvsError, Result};
+
+/// 按照 Redis `sdssplitargs` 的规则拆分一行内联指令
+///
+/// 参数之间以空白分隔。双引号中支持 `\n`、`\r`、`\t`、`\b`、`\a`
+/// 以及 `\xHH` 形式的转义，单引号中只支持 `\'`。
+/// 结束的引号之后必须是空白或者行尾。
+pub(crate) fn split_args(line: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        while pos < line.len() && line[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            let c = match line.get(pos) {
+                Some(&c) => c,
+                None if in_double_quotes || in_single_quotes => {
+                    return Err(unbalanced_quotes());
+                }
+                None => break,
+            };
+
+            if in_double_quotes {
+                if c == b'\\' && line.get(pos + 1) == Some(&b'x') {
+                    if let Some(byte) = line.get(pos + 2..pos + 4).and_then(parse_hex) {
+                        arg.push(byte);
+                        pos += 4;
+                        continue;
+                    }
+                }
+                match c {
+                    b'\\' if pos + 1 < line.len() => {
+                        pos += 1;
+                        arg.push(match line[pos] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    b'"' => {
+                        // 结束的引号之后必须是空白或者行尾
+                        if line.get(pos + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(unbalanced_quotes());
+                        }
+                        pos += 1;
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            } else if in_single_quotes {
+                match c {
+                    b'\\' if line.get(pos + 1) == Some(&b'\'') => {
+                        pos += 1;
+                        arg.push(b'\'');
+                    }
+                    b'\'' => {
+                        if line.get(pos + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(unbalanced_quotes());
+                        }
+                        pos += 1;
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            } else {
+                match c {
+                    c if c.is_ascii_whitespace() => break,
+                    b'"' => in_double_quotes = true,
+                    b'\'' => in_single_quotes = true,
+                    _ => arg.push(c),
+                }
+            }
+            pos += 1;
+        }
+        args.push(arg);
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u8> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok())
+}
+
+fn unbalanced_quotes() -> KvsError {
+    KvsError::Protocol("unbalanced quotes in request".to_owned())
+}
@@ -0,0 +1,280 @@
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
+
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{Db, IVec, Transactional, Tree};
+
+use crate::kv::{expiry, incr, now_millis};
+use crate::{BatchOp, KvsEngine, KvsError, Result, ScanBytes, WriteBatch};
+
+// name of the tree that maps keys to their expiry.
+const TTL_TREE: &str = "ttl";
+
+/// Wrapper of `sled::Db`.
+///
+/// `sled::Db` is already thread-safe, so cloning a `SledKvsEngine` only clones
+/// the handle to the same database.
+///
+/// Expiries are kept in a separate tree, as milliseconds since the Unix epoch.
+/// Expired keys are hidden from reads and deleted lazily by the next write
+/// that touches them.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    ttl: Tree,
+}
+
+impl SledKvsEngine {
+    /// Creates a `SledKvsEngine` from `sled::Db`.
+    pub fn new(db: Db) -> Result<Self> {
+        let ttl = db.open_tree(TTL_TREE)?;
+        Ok(SledKvsEngine { db, ttl })
+    }
+
+    /// Opens a sled database in the given directory.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        SledKvsEngine::new(sled::open(path)?)
+    }
+
+    /// Runs `f` in a transaction over the values and the expiries, then
+    /// flushes the database.
+    fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<R, KvsError>,
+    {
+        let result = (&*self.db, &self.ttl).transaction(|(db, ttl)| f(db, ttl));
+        let result = match result {
+            Ok(result) => result,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+        self.db.flush()?;
+        Ok(result)
+    }
+
+    /// Converts the entries of a sled iterator to key/value pairs, skipping
+    /// expired keys.
+    fn live_entries<'a>(
+        &'a self,
+        iter: impl Iterator<Item = sled::Result<(IVec, IVec)>> + 'a,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        iter.filter_map(move |entry| {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.is_expired(&key) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok((key.to_vec(), value.to_vec()))),
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    /// Returns whether the key has expired, outside of a transaction.
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .ttl
+            .get(key)?
+            .is_some_and(|expires_at| decode_expiry(&expires_at) <= now_millis()))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(|db, ttl| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            ttl.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.transaction(|db, ttl| {
+            if live_value(db, ttl, &key)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            db.remove(key.as_slice())?;
+            ttl.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<Vec<bool>> {
+        // a transaction rather than `sled::Batch`, so that removing a missing
+        // key can abort the whole batch.
+        self.transaction(|db, ttl| {
+            let mut applied = Vec::with_capacity(batch.len());
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        db.insert(key.as_slice(), value.as_slice())?;
+                    }
+                    BatchOp::Remove { key } => {
+                        if live_value(db, ttl, key)?.is_none() {
+                            return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+                        }
+                        db.remove(key.as_slice())?;
+                    }
+                    BatchOp::Delete { key } => {
+                        if live_value(db, ttl, key)?.is_none() {
+                            applied.push(false);
+                            continue;
+                        }
+                        db.remove(key.as_slice())?;
+                    }
+                }
+                ttl.remove(op_key(op))?;
+                applied.push(true);
+            }
+            Ok(applied)
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.transaction(|db, ttl| {
+            let current = live_value(db, ttl, &key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => db.insert(key.as_slice(), value.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+            ttl.remove(key.as_slice())?;
+            Ok(true)
+        })
+    }
+
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.transaction(|db, ttl| {
+            if live_value(db, ttl, &key)?.is_none() {
+                return Ok(false);
+            }
+            db.insert(key.as_slice(), value.as_slice())?;
+            ttl.remove(key.as_slice())?;
+            Ok(true)
+        })
+    }
+
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.transaction(|db, ttl| {
+            let current = live_value(db, ttl, &key)?;
+            let value =
+                incr(current.as_deref(), delta).map_err(ConflictableTransactionError::Abort)?;
+            // like in Redis, a counter keeps its expiry.
+            db.insert(key.as_slice(), value.to_string().as_bytes())?;
+            Ok(value)
+        })
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.transaction(|db, ttl_tree| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            ttl_tree.insert(key.as_slice(), &expiry(ttl).to_be_bytes())?;
+            Ok(())
+        })
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        self.transaction(|db, ttl_tree| {
+            if live_value(db, ttl_tree, &key)?.is_none() {
+                return Ok(false);
+            }
+            ttl_tree.insert(key.as_slice(), &expiry(ttl).to_be_bytes())?;
+            Ok(true)
+        })
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        if !self.db.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        let now = now_millis();
+        match self
+            .ttl
+            .get(&key)?
+            .map(|expires_at| decode_expiry(&expires_at))
+        {
+            Some(expires_at) if expires_at <= now => Err(KvsError::KeyNotFound),
+            Some(expires_at) => Ok(Some(Duration::from_millis(expires_at - now))),
+            None => Ok(None),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        self.transaction(|db, ttl| {
+            if live_value(db, ttl, &key)?.is_none() {
+                return Ok(false);
+            }
+            Ok(ttl.remove(key.as_slice())?.is_some())
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_> {
+        Box::new(self.live_entries(self.db.range(range)).take(limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> ScanBytes<'_> {
+        Box::new(self.live_entries(self.db.scan_prefix(prefix)))
+    }
+
+    fn scan_rev_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_> {
+        Box::new(self.live_entries(self.db.range(range).rev()).take(limit))
+    }
+}
+
+/// Reads the value of a key in a transaction, deleting it if it has expired.
+fn live_value(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<IVec>, KvsError> {
+    if let Some(expires_at) = ttl.get(key)? {
+        if decode_expiry(&expires_at) <= now_millis() {
+            db.remove(key)?;
+            ttl.remove(key)?;
+            return Ok(None);
+        }
+    }
+    Ok(db.get(key)?)
+}
+
+/// Returns the key written by a batch operation.
+fn op_key(op: &BatchOp) -> &[u8] {
+    match op {
+        BatchOp::Set { key, .. } | BatchOp::Remove { key } | BatchOp::Delete { key } => key,
+    }
+}
+
+/// Decodes an expiry stored in the ttl tree, treating a malformed one as expired.
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut expires_at = [0; 8];
+    if bytes.len() != expires_at.len() {
+        return 0;
+    }
+    expires_at.copy_from_slice(bytes);
+    u64::from_be_bytes(expires_at)
+}
@@ -0,0 +1,107 @@
+//! Read-only snapshots of a `KvStore`.
+
+use std::iter;
+use std::ops::{Bound, RangeBounds};
+
+use crate::engine::{bytes_bound, string_pair};
+use crate::kv::ViewPin;
+use crate::{KvIterator, KvStore, Result, Scan, ScanBytes};
+
+/// A read-only view of a `KvStore` as of `KvStore::snapshot`.
+///
+/// Reads from a snapshot ignore every write made after it was taken, so all
+/// of them are consistent with each other. While the snapshot is alive, the
+/// index keeps the versions it reads and compactions keep the log files they
+/// are in. A snapshot owns its handle to the store and can be sent to
+/// another thread.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("balance:a".to_owned(), "10".to_owned())?;
+/// let snapshot = store.snapshot();
+/// store.set("balance:a".to_owned(), "0".to_owned())?;
+/// assert_eq!(snapshot.get("balance:a".to_owned())?, Some("10".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    store: KvStore,
+    view: ViewPin,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: KvStore, view: ViewPin) -> Snapshot {
+        Snapshot { store, view }
+    }
+
+    /// Gets the value of a key when the snapshot was taken.
+    ///
+    /// Returns `None` if the key did not exist or has expired since.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Gets the binary value of a key when the snapshot was taken.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.get_at(key, self.view.seq())
+    }
+
+    /// Iterates over at most `limit` key/value pairs with keys in `range`, in
+    /// key order.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Scan<'_> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Box::new(self.scan_bytes(range, limit).map(string_pair))
+    }
+
+    /// Iterates over the key/value pairs whose keys start with `prefix`, in
+    /// key order.
+    pub fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        Box::new(self.scan_prefix_bytes(prefix.as_bytes()).map(string_pair))
+    }
+
+    /// Iterates over at most `limit` binary key/value pairs with keys in
+    /// `range`, in key order.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytes<'_> {
+        let mut iter = self.iter();
+        match range.start_bound() {
+            Bound::Included(key) => iter.seek(key),
+            Bound::Excluded(key) => iter.seek_for_prev(key),
+            Bound::Unbounded => iter.seek_to_first(),
+        }
+        let end = range.end_bound().cloned();
+        let entries =
+            iter::from_fn(move || iter.next_bytes()).take_while(move |entry| match entry {
+                Ok((key, _)) => (Bound::Unbounded, end.as_ref()).contains(key),
+                Err(_) => true,
+            });
+        Box::new(entries.take(limit))
+    }
+
+    /// Iterates over the binary key/value pairs whose keys start with
+    /// `prefix`, in key order.
+    pub fn scan_prefix_bytes(&self, prefix: &[u8]) -> ScanBytes<'_> {
+        let mut iter = self.iter();
+        iter.seek(prefix);
+        let prefix = prefix.to_vec();
+        Box::new(
+            iter::from_fn(move || iter.next_bytes()).take_while(move |entry| match entry {
+                Ok((key, _)) => key.starts_with(&prefix),
+                Err(_) => true,
+            }),
+        )
+    }
+
+    /// Returns an iterator over the snapshot, positioned before the first key.
+    pub fn iter(&self) -> KvIterator<'_> {
+        KvIterator::new(&self.store, self.view.clone())
+    }
+}
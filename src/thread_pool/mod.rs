@@ -0,0 +1,30 @@
+//! Thread pools used by `kvs-server` to serve connections concurrently.
+
+use crate::Result;
+
+pub use naive::NaiveThreadPool;
+pub use shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod shared_queue;
+
+/// A pool of threads that executes jobs.
+pub trait ThreadPool {
+    /// Creates a new thread pool, immediately spawning the specified number of threads.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if any thread fails to spawn. All previously-spawned
+    /// threads are terminated.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Spawns a function into the thread pool.
+    ///
+    /// Spawning always succeeds, but if the function panics the thread pool
+    /// continues to operate with the same number of threads.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
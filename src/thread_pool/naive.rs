@@ -0,0 +1,21 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// A "thread pool" that spawns a new thread for every job.
+///
+/// It does not actually reuse threads, the number passed to `new` is ignored.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
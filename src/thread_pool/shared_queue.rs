@@ -0,0 +1,63 @@
+use super::ThreadPool;
+use crate::{Result, LOGGER};
+use slog::error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool with a fixed number of threads pulling jobs from a shared queue.
+///
+/// A job that panics does not take its worker thread down with it, so the
+/// pool keeps the same number of threads for its whole lifetime. Dropping the
+/// pool closes the queue; workers exit after finishing the jobs already queued.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for id in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            // If spawning fails, `sender` is dropped on return and the
+            // threads spawned so far exit.
+            thread::Builder::new()
+                .name(format!("kvs-worker-{}", id))
+                .spawn(move || run_jobs(&receiver))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+/// Runs jobs from the queue until the pool is dropped.
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released as soon as a job is received, so other workers
+        // can pick up jobs while this one is running.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            let thread = thread::current();
+            error!(
+                LOGGER,
+                "A job panicked in {}",
+                thread.name().unwrap_or("worker")
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Optimistic transactions of a `KvStore`.
+
+use std::collections::HashMap;
+
+use crate::kv::Command;
+use crate::{KvStore, KvsError, Result};
+
+// a value together with its absolute expiry.
+type Expiring = (Vec<u8>, Option<u64>);
+
+/// A read-modify-write transaction started by `KvStore::transaction`.
+///
+/// Writes are buffered until `commit`, and `get` sees the writes made earlier
+/// in the transaction. Nothing is locked while the transaction runs: `commit`
+/// fails with `KvsError::Conflict` if another writer modified any key read by
+/// the transaction in the meantime, and the caller may retry it from the start.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut txn = store.transaction();
+/// let count = txn.get("count".to_owned())?.unwrap_or_else(|| "0".to_owned());
+/// let count = count.parse::<u64>().unwrap() + 1;
+/// txn.set("count".to_owned(), count.to_string());
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction {
+    store: KvStore,
+    // the sequence number first seen for each key read from the store, `None`
+    // for a key that did not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // the value and expiry of each key written by the transaction, `None` once
+    // removed.
+    writes: HashMap<Vec<u8>, Option<Expiring>>,
+    cmds: Vec<Command>,
+}
+
+impl Transaction {
+    pub(crate) fn new(store: KvStore) -> Transaction {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+            cmds: Vec::new(),
+        }
+    }
+
+    /// Gets the value of a key, as written by this transaction or as stored.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Gets the binary value of a key, as written by this transaction or as
+    /// stored.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(&key)?.map(|(value, _)| value))
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Sets the binary value of a key when the transaction commits.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.set_expiring(key, value, None);
+    }
+
+    /// Removes a key when the transaction commits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist. Its
+    /// existence is read, so the commit fails if another writer changes it.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Removes a binary key when the transaction commits, like `remove`.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.read(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key.clone(), None);
+        self.cmds.push(Command::remove(key));
+        Ok(())
+    }
+
+    /// Writes all writes of the transaction atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` without writing anything if a key read
+    /// by the transaction has been modified since.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn commit(self) -> Result<()> {
+        if self.reads.is_empty() && self.cmds.is_empty() {
+            return Ok(());
+        }
+        self.store
+            .commit_transaction(self.reads.into_iter().collect(), self.cmds)
+    }
+
+    /// Gets the value of a key together with its expiry.
+    pub(crate) fn read(&mut self, key: &[u8]) -> Result<Option<Expiring>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let versioned = self.store.get_versioned(key)?;
+        // a later read that sees another value fails the commit anyway.
+        self.reads.entry(key.to_vec()).or_insert(versioned.seq);
+        let expires_at = versioned.expires_at;
+        Ok(versioned.value.map(|value| (value, expires_at)))
+    }
+
+    /// Sets the value of a key with the given absolute expiry when the
+    /// transaction commits.
+    pub(crate) fn set_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        self.writes
+            .insert(key.clone(), Some((value.clone(), expires_at)));
+        self.cmds
+            .push(Command::set_expiring(key, value, expires_at));
+    }
+}
@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{RespDecoder, RespValue};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -172,6 +175,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -212,6 +216,80 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+#[cfg(feature = "sled")]
+fn cli_default_to_recorded_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    // Without --engine the server should reuse the recorded engine
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Storage engine: sled"));
+}
+
+// Without a recorded engine, the engine of existing data files is used
+#[test]
+#[cfg(feature = "sled")]
+fn cli_infer_engine_from_data_files() {
+    let temp_dir = TempDir::new().unwrap();
+    File::create(temp_dir.path().join("1.log")).unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("engine").exists());
+
+    // an invalid recorded engine is ignored in favor of the data files
+    fs::write(temp_dir.path().join("engine"), "invalid").unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "invalid"
+    );
+}
+
+// The engine is only recorded once it has been opened successfully
+#[test]
+fn cli_record_engine_after_open() {
+    let temp_dir = TempDir::new().unwrap();
+    File::create(temp_dir.path().join("1.log")).unwrap();
+    // the log file of the next generation cannot be created
+    fs::create_dir(temp_dir.path().join("2.log")).unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -326,12 +404,550 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// A value larger than a single socket read should be stored and returned intact.
+#[test]
+fn cli_access_server_large_value() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "v".repeat(64 * 1024);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+/// Builds a RESP bulk string.
+fn bulk(s: &str) -> RespValue {
+    RespValue::BulkStrings(Some(s.as_bytes().to_vec()))
+}
+
+/// Sends a raw RESP request and decodes the reply.
+fn resp_request(stream: &mut TcpStream, request: &[u8]) -> RespValue {
+    stream.write_all(request).unwrap();
+    let mut decoder = RespDecoder::new();
+    let mut chunk = [0; 256];
+    loop {
+        if let Some(reply) = decoder.decode_next().unwrap() {
+            return reply;
+        }
+        let len = stream.read(&mut chunk).unwrap();
+        assert!(len > 0, "server closed the connection");
+        decoder.feed(&chunk[..len]);
+    }
+}
+
+fn assert_resp_reply(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+    assert_eq!(
+        String::from_utf8_lossy(&resp_request(stream, request).to_bytes()),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+// The server should also answer Redis clients speaking RESP on the same port.
+#[test]
+fn resp_access_server() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_resp_reply(&mut stream, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n");
+    assert_resp_reply(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+        b"+OK\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        b"$6\r\nvalue1\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*2\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n",
+        b":1\r\n",
+    );
+    assert_resp_reply(&mut stream, b"*2\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n", b":1\r\n");
+    assert_resp_reply(&mut stream, b"*2\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n", b":0\r\n");
+    assert_resp_reply(
+        &mut stream,
+        b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        b"$-1\r\n",
+    );
+    // several keys: missing ones are skipped, duplicates count for EXISTS.
+    assert_resp_reply(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$1\r\na\r\n",
+        b"+OK\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$1\r\nb\r\n",
+        b"+OK\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*4\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey1\r\n$4\r\nnope\r\n",
+        b":2\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*4\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nnope\r\n",
+        b":2\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*3\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+        b":0\r\n",
+    );
+    // binary keys and values, including CRLF inside a bulk string.
+    assert_resp_reply(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$2\r\n\x00\xff\r\n$4\r\n\xfe\x00\r\n\r\n",
+        b"+OK\r\n",
+    );
+    assert_eq!(
+        resp_request(&mut stream, b"*2\r\n$3\r\nGET\r\n$2\r\n\x00\xff\r\n"),
+        RespValue::BulkStrings(Some(b"\xfe\x00\r\n".to_vec()))
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*1\r\n$7\r\nUNKNOWN\r\n",
+        b"-ERR unknown command 'unknown'\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"*1\r\n$9\r\nFOO\r\n+BAR\r\n",
+        b"-ERR unknown command 'foo  +bar'\r\n",
+    );
+
+    // switch the connection to RESP3.
+    assert_resp_reply(
+        &mut stream,
+        b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n",
+        b"-NOPROTO unsupported protocol version\r\n",
+    );
+    match resp_request(&mut stream, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n") {
+        RespValue::Map(entries) => assert!(entries.contains(&(
+            RespValue::BulkStrings(Some(b"proto".to_vec())),
+            RespValue::Integer(3)
+        ))),
+        other => panic!("unexpected HELLO reply: {:?}", other),
+    }
+    assert_resp_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", b"_\r\n");
+
+    // inline commands, as typed into telnet or nc.
+    assert_resp_reply(&mut stream, b"SET key2 \"hello world\"\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"get key2\n", b"$11\r\nhello world\r\n");
+    match resp_request(&mut stream, b"*1\r\n$4\r\nINFO\r\n") {
+        RespValue::Map(entries) => assert!(entries.contains(&(
+            RespValue::BulkStrings(Some(b"kvs_version".to_vec())),
+            RespValue::BulkStrings(Some(env!("CARGO_PKG_VERSION").as_bytes().to_vec()))
+        ))),
+        other => panic!("unexpected INFO reply: {:?}", other),
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// An idle connection must not block other clients.
+#[test]
+fn cli_access_server_with_idle_client() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // half-written request which the server keeps waiting on.
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.write_all(b"*2\r\n$3\r\nGET\r\n").unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    drop(idle);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn server_cli_invalid_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Batches from kvs-client and MULTI/EXEC from Redis clients should apply atomically.
+#[test]
+fn cli_access_server_batch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "batch", "set", "key1", "value1", "set", "key2", "value2", "rm", "key1", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    // removing a missing key fails the whole batch
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "batch", "set", "key3", "value3", "rm", "key1", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "set", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_resp_reply(&mut stream, b"GET key2\r\n", b"$6\r\nvalue2\r\n");
+    assert_resp_reply(&mut stream, b"EXISTS key1\r\n", b":0\r\n");
+    assert_resp_reply(&mut stream, b"EXISTS key3\r\n", b":0\r\n");
+
+    assert_resp_reply(&mut stream, b"EXEC\r\n", b"-ERR EXEC without MULTI\r\n");
+    assert_resp_reply(&mut stream, b"MULTI\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"SET key4 value4\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"DEL key2\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"EXEC\r\n", b"*2\r\n+OK\r\n:1\r\n");
+    assert_resp_reply(&mut stream, b"GET key4\r\n", b"$6\r\nvalue4\r\n");
+    assert_resp_reply(&mut stream, b"EXISTS key2\r\n", b":0\r\n");
+
+    // deleting a missing key inside a transaction is not an error
+    assert_resp_reply(&mut stream, b"MULTI\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"SET key6 value6\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"DEL nope\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"DEL key4\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"EXEC\r\n", b"*3\r\n+OK\r\n:0\r\n:1\r\n");
+    assert_resp_reply(&mut stream, b"GET key6\r\n", b"$6\r\nvalue6\r\n");
+    assert_resp_reply(&mut stream, b"EXISTS key4\r\n", b":0\r\n");
+
+    // an error while queueing discards the transaction
+    assert_resp_reply(&mut stream, b"MULTI\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"SET key5 value5\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(
+        &mut stream,
+        b"SET key5\r\n",
+        b"-ERR wrong number of arguments for 'set' command\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"EXEC\r\n",
+        b"-EXECABORT Transaction discarded because of previous errors.\r\n",
+    );
+    assert_resp_reply(&mut stream, b"MULTI\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"SET key5 value5\r\n", b"+QUEUED\r\n");
+    assert_resp_reply(&mut stream, b"DISCARD\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"EXISTS key5\r\n", b":0\r\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_access_server_conditional_writes() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "--xx", "key1", "value1"])
+        .assert()
+        .code(2)
+        .stderr(contains("Condition not met"));
+    client(&["set", "--nx", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["set", "--nx", "key1", "value2"]).assert().code(2);
+    client(&["set", "--nx", "--xx", "key1", "value2"])
+        .assert()
+        .failure();
+    client(&["cas", "key1", "--expected", "value2", "--new", "value3"])
+        .assert()
+        .code(2);
+    client(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+    client(&["cas", "key1", "--expected", "value3"])
+        .assert()
+        .success();
+    client(&["cas", "key1", "--new", "value4"])
+        .assert()
+        .success();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_resp_reply(&mut stream, b"SETNX key1 value5\r\n", b":0\r\n");
+    assert_resp_reply(&mut stream, b"SETNX key2 value5\r\n", b":1\r\n");
+    assert_resp_reply(&mut stream, b"SET key3 value5 XX\r\n", b"$-1\r\n");
+    assert_resp_reply(&mut stream, b"SET key3 value5 NX\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"SET key3 value6 XX\r\n", b"+OK\r\n");
+    assert_resp_reply(&mut stream, b"GET key3\r\n", b"$6\r\nvalue6\r\n");
+    assert_resp_reply(&mut stream, b"GET key1\r\n", b"$6\r\nvalue4\r\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_access_server_counters() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["incr", "counter"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["incr", "counter", "--by", "10"])
+        .assert()
+        .success()
+        .stdout("11\n");
+    client(&["decr", "counter", "--by", "-4"])
+        .assert()
+        .success()
+        .stdout("15\n");
+    client(&["decr", "counter"])
+        .assert()
+        .success()
+        .stdout("14\n");
+    client(&["incr", "counter", "--by", "ten"])
+        .assert()
+        .failure();
+    client(&["set", "name", "kvs"]).assert().success();
+    client(&["incr", "name"])
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_resp_reply(&mut stream, b"INCR counter\r\n", b":15\r\n");
+    assert_resp_reply(&mut stream, b"DECR counter\r\n", b":14\r\n");
+    assert_resp_reply(&mut stream, b"INCRBY counter 6\r\n", b":20\r\n");
+    assert_resp_reply(&mut stream, b"DECRBY counter 25\r\n", b":-5\r\n");
+    assert_resp_reply(
+        &mut stream,
+        b"INCR name\r\n",
+        b"-ERR value is not an integer or out of range\r\n",
+    );
+    assert_resp_reply(&mut stream, b"GET counter\r\n", b"$2\r\n-5\r\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_access_server_scan() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    for (key, value) in &[("a", "1"), ("b", "2"), ("c", "3"), ("user:1", "x")] {
+        client(&["set", key, value]).assert().success();
+    }
+
+    // page through all keys two at a time
+    let output = client(&["scan", "--count", "2"]).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    let cursor = lines.next().unwrap().to_owned();
+    assert_ne!(cursor, "0");
+    assert_eq!(lines.collect::<Vec<_>>(), vec!["a\t1", "b\t2"]);
+    client(&["scan", "--count", "2", "--cursor", &cursor])
+        .assert()
+        .success()
+        .stdout(contains("c\t3\nuser:1\tx\n"));
+    client(&["scan", "--prefix", "user:"])
+        .assert()
+        .success()
+        .stdout("0\nuser:1\tx\n");
+    // page backwards from the last key
+    let output = client(&["scan", "--count", "3", "--reverse"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    let cursor = lines.next().unwrap().to_owned();
+    assert_eq!(lines.collect::<Vec<_>>(), vec!["user:1\tx", "c\t3", "b\t2"]);
+    client(&["scan", "--count", "3", "--reverse", "--cursor", &cursor])
+        .assert()
+        .success()
+        .stdout("0\na\t1\n");
+    client(&["scan", "--cursor", "bogus"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid cursor"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    match resp_request(&mut stream, b"SCAN 0 COUNT 3\r\n") {
+        RespValue::Array(reply) => {
+            assert_eq!(reply.len(), 2);
+            assert_eq!(
+                reply[1],
+                RespValue::Array(vec![bulk("a"), bulk("b"), bulk("c")])
+            );
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_resp_reply(
+        &mut stream,
+        b"SCAN 0 MATCH user:*\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:1\r\n",
+    );
+    assert_resp_reply(
+        &mut stream,
+        b"SCAN 0 MATCH b* REV\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n",
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
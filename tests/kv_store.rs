@@ -1,4 +1,7 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SyncPolicy, WriteBatch};
+use std::fs;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +9,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +19,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +30,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +39,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +51,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +67,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +75,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +87,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +117,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +127,1036 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Clones of a store should see each other's writes across threads
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}_{}", t, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", t, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", t, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Readers should keep getting the latest values while a writer triggers compaction
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..200 {
+                for key_id in 0..100 {
+                    store
+                        .set(format!("key{}", key_id), format!("{}", iter))
+                        .unwrap();
+                }
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id)).unwrap();
+                        assert!(value.is_some());
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A clone that read from a log file before compaction should still read after it is removed
+#[test]
+fn get_after_compaction_from_clone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let reader = store.clone();
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+
+    store.compact()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+    store.set("key".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should report the generation and offset of a record with a bad checksum
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the first record, which belongs to "value1"
+    let log = temp_dir.path().join("1.log");
+    let mut content = std::fs::read(&log)?;
+    let first_len = content.len() / 2;
+    content[first_len - 1] ^= 0xff;
+    std::fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset, .. }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, 0);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    Ok(())
+}
+
+// Should load generations written in the legacy JSON format
+#[test]
+fn load_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // compaction rewrites the legacy records in the binary format
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should drop a partially written record at the end of the newest log
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let full_len = std::fs::metadata(&log)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(full_len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(std::fs::metadata(&log)?.len(), full_len / 2);
+
+    // the truncated log is no longer the newest one after new writes
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should open a store whose compaction was cut off by a crash
+#[test]
+fn recover_torn_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["key1", "key2", "key3"] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    drop(store);
+    let old_log = fs::read(temp_dir.path().join("1.log"))?;
+
+    // compacts generations 1 and 2 into 3, then writes to 4.
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+
+    // a crash before the compaction file was complete leaves the old logs and
+    // a partial compaction file as the newest log.
+    fs::write(temp_dir.path().join("1.log"), &old_log)?;
+    fs::remove_file(temp_dir.path().join("4.log"))?;
+    let compaction_log = temp_dir.path().join("3.log");
+    let len = fs::metadata(&compaction_log)?.len();
+    let file = fs::OpenOptions::new().write(true).open(&compaction_log)?;
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["key1", "key2", "key3"] {
+        assert_eq!(store.get(key.to_string())?, Some(format!("{}-value", key)));
+    }
+
+    Ok(())
+}
+
+// Should drop a last record that fails its checksum, but not one in an older log
+#[test]
+fn recover_checksum_failing_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = std::fs::read(&log)?;
+    *content.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&log, &content)?;
+    // a newer generation makes the damaged log an older one
+    std::fs::write(temp_dir.path().join("2.log"), b"")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset, .. }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, content.len() as u64 / 2);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    std::fs::remove_file(temp_dir.path().join("2.log"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should refuse to open a log whose record has a corrupted length, rather than
+// taking it for a torn tail and dropping the records after it
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the high byte of the key length of the first record.
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    content[2] ^= 0x01;
+    fs::write(&log, &content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset, .. }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, 0);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(fs::read(&log)?, content);
+
+    Ok(())
+}
+
+// Should drop zeros at the end of the newest log, left by a crash after the
+// file was extended
+#[test]
+fn recover_zeroed_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let len = content.len() as u64;
+    content.extend_from_slice(&[0; 20]);
+    fs::write(&log, &content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), len);
+
+    Ok(())
+}
+
+// Should persist data under every sync policy
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        SyncPolicy::Bytes(64),
+    ];
+    for &sync_policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            sync_policy,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.compact()?;
+        thread::sleep(Duration::from_millis(20));
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Concurrent writes committed in groups should each get their own result
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_policy: SyncPolicy::Always,
+        group_commit_delay: Duration::from_millis(1),
+        group_commit_bytes: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}_{}", t, i);
+                    store.set(key.clone(), "old".to_owned()).unwrap();
+                    store.remove(key.clone()).unwrap();
+                    assert!(matches!(
+                        store.remove(key.clone()),
+                        Err(KvsError::KeyNotFound)
+                    ));
+                    store.set(key, format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for t in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}_{}", t, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Should apply a batch of writes as a whole
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.remove("key3".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // removing a missing key fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key1".to_owned());
+    assert!(matches!(store.write(batch), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // deleting a missing key is skipped
+    let mut batch = WriteBatch::new();
+    batch.set("key5".to_owned(), "value5".to_owned());
+    batch.delete("key1".to_owned());
+    batch.delete("key5".to_owned());
+    assert_eq!(store.write(batch)?, vec![true, false, true]);
+    assert_eq!(store.get("key5".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A batch torn at the end of the log should be dropped as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    // cut the batch record right after its first nested record
+    let log = temp_dir.path().join("1.log");
+    let full_len = std::fs::metadata(&log)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(full_len - 20)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // a transaction sees its own writes, and nothing is written before commit
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    txn.remove("key1".to_owned())?;
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    assert!(matches!(
+        txn.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a key modified after it was read fails the commit
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    txn.set("key3".to_owned(), "value3".to_owned());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // so does a key that did not exist when it was read
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+
+    // moving values during a compaction does not modify them
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    txn.set("key1".to_owned(), "value4".to_owned());
+    txn.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                loop {
+                    let mut txn = store.transaction();
+                    let counter: u64 = txn
+                        .get("counter".to_owned())
+                        .unwrap()
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    txn.set("counter".to_owned(), (counter + 1).to_string());
+                    match txn.commit() {
+                        Ok(()) => break,
+                        Err(KvsError::Conflict) => continue,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if_present("key1".to_owned(), "value1".to_owned())?);
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(store.set_if_present("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let cas = |expected: Option<&str>, new: Option<&str>| {
+        store.compare_and_swap(
+            "key1".to_owned(),
+            expected.map(str::to_owned),
+            new.map(str::to_owned),
+        )
+    };
+    assert!(!cas(Some("value1"), Some("value3"))?);
+    assert!(!cas(None, Some("value3"))?);
+    assert!(cas(Some("value2"), Some("value3"))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(cas(Some("value3"), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(cas(None, None)?);
+    assert!(cas(None, Some("value4"))?);
+
+    // exactly one of the threads racing for a lock acquires it
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store
+                    .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let acquired = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|&acquired| acquired)
+        .count();
+    assert_eq!(acquired, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr_by("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(matches!(
+        store.incr_by("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr_by("max".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    store.incr_by("hits".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("400".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn expiration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        expiry_sample_interval: Duration::from_millis(10),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let hour = Duration::from_secs(3600);
+
+    store.set_with_ttl("session".to_owned(), "token".to_owned(), hour)?;
+    store.set("user".to_owned(), "alice".to_owned())?;
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+    assert!(store.ttl("session".to_owned())?.unwrap() <= hour);
+    assert_eq!(store.ttl("user".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert!(store.expire("user".to_owned(), hour)?);
+    assert!(!store.expire("missing".to_owned(), hour)?);
+    assert!(store.persist("user".to_owned())?);
+    assert!(!store.persist("user".to_owned())?);
+    assert_eq!(store.ttl("user".to_owned())?, None);
+
+    // a counter keeps its expiry, while a plain set clears it
+    store.set_with_ttl("hits".to_owned(), "1".to_owned(), hour)?;
+    assert_eq!(store.incr_by("hits".to_owned(), 1)?, 2);
+    assert!(store.ttl("hits".to_owned())?.is_some());
+    store.set("hits".to_owned(), "0".to_owned())?;
+    assert_eq!(store.ttl("hits".to_owned())?, None);
+
+    for i in 0..100 {
+        store.set_with_ttl(
+            format!("temp{}", i),
+            "value".repeat(100),
+            Duration::from_millis(50),
+        )?;
+    }
+    store.expire("user".to_owned(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("temp0".to_owned())?, None);
+    assert_eq!(store.get("user".to_owned())?, None);
+    assert!(matches!(
+        store.remove("user".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    // an expired key can be set again
+    assert!(store.set_if_absent("temp1".to_owned(), "again".to_owned())?);
+
+    // the compaction drops expired values from the log
+    store.compact()?;
+    let dir_size = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum::<u64>();
+    assert!(
+        dir_size < 1000,
+        "expired values were not dropped: {}",
+        dir_size
+    );
+
+    // expiries survive a reopen
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("session".to_owned())?, Some("token".to_owned()));
+    assert!(store.ttl("session".to_owned())?.is_some());
+    assert_eq!(store.get("temp1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("temp2".to_owned())?, None);
+
+    Ok(())
+}
+
+// An expired value in the log should hide older values of its key after a reopen
+#[test]
+fn load_expired_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:3", "order:1", "user:1", "user:2", "zone"] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    store.set_with_ttl(
+        "user:0".to_owned(),
+        "expired".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let keys = |scan: kvs::Scan| -> Result<Vec<String>> {
+        scan.map(|entry| entry.map(|(key, _)| key)).collect()
+    };
+    let all = store.scan(.., usize::MAX).collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 5);
+    assert_eq!(all[0], ("order:1".to_owned(), "order:1-value".to_owned()));
+    assert_eq!(
+        keys(store.scan("user:1".to_owned()..="user:3".to_owned(), 2))?,
+        vec!["user:1", "user:2"]
+    );
+    assert_eq!(
+        keys(store.scan("user:2".to_owned().., usize::MAX))?,
+        vec!["user:2", "user:3", "zone"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("user:"))?,
+        vec!["user:1", "user:2", "user:3"]
+    );
+    assert!(keys(store.scan_prefix("missing"))?.is_empty());
+
+    // keys removed during a scan are skipped, keys not reached yet are read lazily
+    let mut scan = store.scan_prefix("user:");
+    assert_eq!(scan.next().unwrap()?.0, "user:1");
+    store.remove("user:2".to_owned())?;
+    store.set("user:3".to_owned(), "new".to_owned())?;
+    assert_eq!(
+        scan.next().unwrap()?,
+        ("user:3".to_owned(), "new".to_owned())
+    );
+    assert!(scan.next().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn iterator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for minute in 0..10 {
+        store.set(format!("event:{:02}", minute), minute.to_string())?;
+    }
+
+    let keys = |entries: Vec<Result<(String, String)>>| -> Result<Vec<String>> {
+        entries
+            .into_iter()
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect()
+    };
+    let mut iter = store.iter();
+    // the latest 3 events before minute 5
+    iter.seek("event:05");
+    let before = (0..3).map_while(|_| iter.prev()).collect();
+    assert_eq!(keys(before)?, vec!["event:04", "event:03", "event:02"]);
+    // moving forward again returns the same pairs
+    assert_eq!(iter.next().unwrap()?.0, "event:02");
+    iter.seek_for_prev("event:05");
+    assert_eq!(iter.prev().unwrap()?.0, "event:05");
+    iter.seek("event:055");
+    assert_eq!(iter.next().unwrap()?.0, "event:06");
+    iter.seek_to_last();
+    assert_eq!(iter.prev().unwrap()?.0, "event:09");
+    assert_eq!(iter.next().unwrap()?.0, "event:09");
+    assert!(iter.next().is_none());
+    iter.seek_to_first();
+    assert!(iter.prev().is_none());
+    assert_eq!(
+        iter.next().unwrap()?,
+        ("event:00".to_owned(), "0".to_owned())
+    );
+
+    // writes made after the iterator was created are not seen, even after
+    // a compaction moves the values
+    store.set("event:00".to_owned(), "new".to_owned())?;
+    store.remove("event:01".to_owned())?;
+    store.set("event:015".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    iter.seek_to_first();
+    assert_eq!(
+        iter.next().unwrap()?,
+        ("event:00".to_owned(), "0".to_owned())
+    );
+    assert_eq!(
+        iter.next().unwrap()?,
+        ("event:01".to_owned(), "1".to_owned())
+    );
+    assert_eq!(iter.next().unwrap()?.0, "event:02");
+    assert_eq!(store.iter().count(), 10);
+    assert_eq!(store.get("event:01".to_owned())?, None);
+
+    // the log files replaced by the compaction are removed once the view is closed
+    let log_files = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert!(log_files() > 2);
+    drop(iter);
+    assert_eq!(log_files(), 2);
+    assert_eq!(store.get("event:00".to_owned())?, Some("new".to_owned()));
+
+    let rev = store.scan_rev("event:02".to_owned()..="event:05".to_owned(), 3);
+    assert_eq!(
+        keys(rev.collect())?,
+        vec!["event:05", "event:04", "event:03"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_iteration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "0".to_owned())?;
+    }
+
+    let writer_store = store.clone();
+    let writer = thread::spawn(move || -> Result<()> {
+        for round in 1..=20 {
+            // every write changes all keys at once
+            let mut batch = WriteBatch::new();
+            for i in 0..100 {
+                batch.set(format!("key{:03}", i), round.to_string());
+            }
+            writer_store.write(batch)?;
+        }
+        Ok(())
+    });
+    for _ in 0..20 {
+        let values = store
+            .iter()
+            .map(|entry| entry.map(|(_, value)| value))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values.len(), 100);
+        assert!(values.iter().all(|value| *value == values[0]));
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["report:1", "report:2", "report:3", "other"] {
+        store.set(key.to_string(), "old".to_owned())?;
+    }
+
+    let snapshot = store.snapshot();
+    store.set("report:1".to_owned(), "new".to_owned())?;
+    store.remove("report:2".to_owned())?;
+    store.set("report:4".to_owned(), "new".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(snapshot.get("report:1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("report:2".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("report:4".to_owned())?, None);
+    let entries = snapshot
+        .scan_prefix("report:")
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("report:1".to_owned(), "old".to_owned()),
+            ("report:2".to_owned(), "old".to_owned()),
+            ("report:3".to_owned(), "old".to_owned()),
+        ]
+    );
+    let keys = snapshot
+        .scan("report:2".to_owned().., 2)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["report:2", "report:3"]);
+    assert_eq!(store.get("report:1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("report:2".to_owned())?, None);
+
+    // a snapshot can be read from another thread while the store changes
+    let reader = thread::spawn(move || -> Result<Option<String>> {
+        let value = snapshot.get("report:1".to_owned());
+        drop(snapshot);
+        value
+    });
+    store.set("report:1".to_owned(), "newer".to_owned())?;
+    assert_eq!(reader.join().unwrap()?, Some("old".to_owned()));
+
+    // the log files kept for the snapshot are removed once it is dropped
+    let log_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert_eq!(log_files, 2);
+
+    Ok(())
+}
+
+// Keys and values that are not UTF-8 should be stored as is
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0x00, 0xff, 0x10];
+    let value = vec![0xfe, 0x00, b'\n', 0x80];
+
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(vec![0x00, 0xff, 0x20], b"text".to_vec())?;
+    store.set_bytes(vec![0x01], vec![])?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(store.get_bytes(vec![0x01])?, Some(vec![]));
+    assert!(matches!(
+        store.get(String::from_utf8_lossy(&key).into_owned()),
+        Ok(None)
+    ));
+    store.set_bytes(b"key1".to_vec(), value.clone())?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::EncodeError(_))
+    ));
+
+    let entries = store
+        .scan_prefix_bytes(&[0x00, 0xff])
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            (key.clone(), value.clone()),
+            (vec![0x00, 0xff, 0x20], b"text".to_vec()),
+        ]
+    );
+    let mut iter = store.iter();
+    iter.seek_to_last();
+    assert_eq!(
+        iter.prev_bytes().unwrap()?,
+        (b"key1".to_vec(), value.clone())
+    );
+    assert_eq!(iter.prev_bytes().unwrap()?, (vec![0x01], vec![]));
+    drop(iter);
+
+    // a snapshot scans binary keys as of when it was taken
+    let snapshot = store.snapshot();
+    store.set_bytes(vec![0x00, 0xff, 0x30], vec![0xff])?;
+    let entries = snapshot
+        .scan_prefix_bytes(&[0x00, 0xff])
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 2);
+    let entries = snapshot
+        .scan_bytes(vec![0x00, 0xff, 0x11]..vec![0x02], 10)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            (vec![0x00, 0xff, 0x20], b"text".to_vec()),
+            (vec![0x01], vec![]),
+        ]
+    );
+    drop(snapshot);
+
+    let mut batch = WriteBatch::new();
+    batch.set(vec![0xc0, 0xaf], vec![0xff; 3]);
+    batch.remove(vec![0x01]);
+    store.write(batch)?;
+
+    // the binary records survive a compaction and reopening the store
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(value));
+    assert_eq!(store.get_bytes(vec![0xc0, 0xaf])?, Some(vec![0xff; 3]));
+    assert_eq!(store.get_bytes(vec![0x01])?, None);
+
+    Ok(())
+}
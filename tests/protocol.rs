@@ -0,0 +1,112 @@
+use kvs::{
+    read_frame, write_frame, ClientCommand, KvsError, Request, Response, Result, FRAME_MAGIC,
+    PROTOCOL_VERSION,
+};
+use std::io::Cursor;
+
+// Several frames written back to back should be read back one at a time,
+// followed by `None` once the stream is exhausted.
+#[test]
+fn multiple_frames_on_one_stream() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(
+        &mut buf,
+        &Request::Command(ClientCommand::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+        }),
+    )?;
+    write_frame(
+        &mut buf,
+        &Request::Command(ClientCommand::Get {
+            key: b"key1".to_vec(),
+        }),
+    )?;
+
+    let mut reader = Cursor::new(buf);
+    match read_frame(&mut reader)? {
+        Some(Request::Command(ClientCommand::Set { key, value })) => {
+            assert_eq!(key, b"key1");
+            assert_eq!(value, b"value1");
+        }
+        other => panic!("unexpected frame: {:?}", other),
+    }
+    match read_frame(&mut reader)? {
+        Some(Request::Command(ClientCommand::Get { key })) => assert_eq!(key, b"key1"),
+        other => panic!("unexpected frame: {:?}", other),
+    }
+    assert!(read_frame::<_, Request>(&mut reader)?.is_none());
+
+    Ok(())
+}
+
+// Binary values larger than any socket buffer should survive a round trip.
+#[test]
+fn large_value_frame() -> Result<()> {
+    let value = vec![0xff; 4 * 1024 * 1024];
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Value(Some(value.clone())))?;
+
+    match read_frame(&mut Cursor::new(buf))? {
+        Some(Response::Value(Some(v))) => assert_eq!(v, value),
+        other => panic!("unexpected frame: {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Binary values should be written as raw bytes rather than a list of numbers.
+#[test]
+fn compact_binary_frame() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Value(Some(vec![0xff; 1024])))?;
+    assert!(buf.len() < 1024 + 16, "frame of {} bytes", buf.len());
+
+    let mut buf = Vec::new();
+    write_frame(
+        &mut buf,
+        &Request::Command(ClientCommand::Set {
+            key: vec![0x00; 1024],
+            value: vec![0x80; 1024],
+        }),
+    )?;
+    assert!(buf.len() < 2048 + 16, "frame of {} bytes", buf.len());
+
+    Ok(())
+}
+
+// A frame cut off in the middle is an error rather than a clean end of stream.
+#[test]
+fn truncated_frame() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Success)?;
+    buf.pop();
+
+    assert!(read_frame::<_, Response>(&mut Cursor::new(&buf)).is_err());
+    assert!(read_frame::<_, Response>(&mut Cursor::new(&buf[..3])).is_err());
+
+    Ok(())
+}
+
+// Data that does not start with the frame header is rejected.
+#[test]
+fn invalid_frame_header() {
+    let mut reader = Cursor::new(b"*1\r\n$4\r\nPING\r\n".to_vec());
+    match read_frame::<_, Request>(&mut reader) {
+        Err(KvsError::Protocol(_)) => {}
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+// A payload that does not decode, such as a byte string longer than the frame,
+// is rejected.
+#[test]
+fn invalid_frame_payload() {
+    let mut frame = vec![FRAME_MAGIC, PROTOCOL_VERSION, 0, 0, 0, 7];
+    // `Response::Value(Some(_))` with a length of 2^32 - 1.
+    frame.extend_from_slice(&[1, 1, 0xfc, 0xff, 0xff, 0xff, 0xff]);
+    match read_frame::<_, Response>(&mut Cursor::new(frame)) {
+        Err(KvsError::Protocol(_)) => {}
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
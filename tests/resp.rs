@@ -0,0 +1,508 @@
+use kvs::{ClientCommand, KvsError, RespDecoder, RespValue, Result};
+use proptest::prelude::*;
+
+fn bulk(s: &str) -> RespValue {
+    RespValue::BulkStrings(Some(s.as_bytes().to_vec()))
+}
+
+// Pipelined values should be decoded one at a time along with the number of
+// bytes each of them consumed.
+#[test]
+fn decode_pipelined_values() -> Result<()> {
+    let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n+OK\r\n:42\r\n$-1\r\n";
+    let decoder = RespDecoder::new();
+
+    let (value, consumed) = decoder.decode(input)?.unwrap();
+    assert_eq!(
+        value,
+        RespValue::Array(vec![bulk("SET"), bulk("key"), bulk("value")])
+    );
+    assert_eq!(consumed, 33);
+    let (value, consumed) = decoder.decode(&input[33..])?.unwrap();
+    assert_eq!(value, RespValue::SimpleStrings("OK".to_owned()));
+    assert_eq!(consumed, 5);
+
+    let mut decoder = RespDecoder::new();
+    decoder.feed(input);
+    let mut values = Vec::new();
+    while let Some(value) = decoder.decode_next()? {
+        values.push(value);
+    }
+    assert_eq!(
+        values,
+        vec![
+            RespValue::Array(vec![bulk("SET"), bulk("key"), bulk("value")]),
+            RespValue::SimpleStrings("OK".to_owned()),
+            RespValue::Integer(42),
+            RespValue::BulkStrings(None),
+        ]
+    );
+    assert_eq!(decoder.buffered(), 0);
+
+    Ok(())
+}
+
+// Feeding a value one byte at a time should only yield it once it is complete.
+#[test]
+fn decode_partial_reads() -> Result<()> {
+    let input = b"*2\r\n*1\r\n:-7\r\n$12\r\nhello\r\nworld\r\n";
+    let mut decoder = RespDecoder::new();
+    assert_eq!(decoder.decode_next()?, None);
+    assert_eq!(decoder.decode(b"")?, None);
+
+    for (i, byte) in input.iter().enumerate() {
+        decoder.feed(&[*byte]);
+        let decoded = decoder.decode_next()?;
+        if i + 1 < input.len() {
+            assert_eq!(decoded, None);
+        } else {
+            assert_eq!(
+                decoded,
+                Some(RespValue::Array(vec![
+                    RespValue::Array(vec![RespValue::Integer(-7)]),
+                    bulk("hello\r\nworld"),
+                ]))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn decode_limits() -> Result<()> {
+    let decoder = RespDecoder::with_limits(4, 2);
+    assert!(decoder.decode(b"$4\r\nabcd\r\n")?.is_some());
+    assert!(decoder.decode(b"$5\r\nabcde\r\n").is_err());
+    assert!(decoder.decode(b"*1\r\n*1\r\n:1\r\n")?.is_some());
+    assert!(decoder.decode(b"*1\r\n*1\r\n*1\r\n:1\r\n").is_err());
+    // an oversized length is rejected before the data arrives.
+    assert!(decoder.decode(b"$1000000\r\n").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn decode_invalid_input() {
+    let decoder = RespDecoder::new();
+    let invalid: Vec<&[u8]> = vec![
+        b"\r\n",
+        b"?what\r\n",
+        b":abc\r\n",
+        b"$-2\r\n",
+        b"$3\r\nabcde\r\n",
+        b"*-5\r\n",
+        b"*4294967296\r\n",
+        b"%4611686018427387904\r\n",
+        b"*1\r\n%4611686018427387904\r\n",
+    ];
+    for input in invalid {
+        match decoder.decode(input) {
+            Err(KvsError::Protocol(_)) => {}
+            other => panic!("unexpected result for {:?}: {:?}", input, other),
+        }
+    }
+}
+
+// A huge map length in a request is rejected rather than overflowing.
+#[test]
+fn decode_request_with_huge_map() {
+    let mut decoder = RespDecoder::new();
+    decoder.feed(b"*1\r\n%4611686018427387904\r\n");
+    match decoder.decode_request() {
+        Err(KvsError::Protocol(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+fn inline(input: &[u8]) -> Result<Option<RespValue>> {
+    let mut decoder = RespDecoder::new();
+    decoder.feed(input);
+    decoder.decode_request()
+}
+
+fn args(args: &[&[u8]]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkStrings(Some(arg.to_vec())))
+            .collect(),
+    )
+}
+
+// Inline commands typed into telnet or nc are split like redis-cli does.
+#[test]
+fn decode_inline_requests() -> Result<()> {
+    assert_eq!(
+        inline(b"SET foo bar\r\n")?,
+        Some(args(&[b"SET", b"foo", b"bar"]))
+    );
+    assert_eq!(inline(b"  get   foo \n")?, Some(args(&[b"get", b"foo"])));
+    assert_eq!(
+        inline(b"SET \"hello world\" 'it\\'s'\r\n")?,
+        Some(args(&[b"SET", b"hello world", b"it's"]))
+    );
+    assert_eq!(
+        inline(b"SET k \"\\x41\\tb\\\\\\\"\"\r\n")?,
+        Some(args(&[b"SET", b"k", b"A\tb\\\""]))
+    );
+    assert_eq!(
+        inline(b"SET k 'no \\n escapes'\r\n")?,
+        Some(args(&[b"SET", b"k", b"no \\n escapes"]))
+    );
+    assert_eq!(inline(b"SET k \"\"\n")?, Some(args(&[b"SET", b"k", b""])));
+    assert_eq!(inline(b"PING")?, None);
+
+    // empty lines are skipped and RESP arrays may follow inline requests.
+    let mut decoder = RespDecoder::new();
+    decoder.feed(b"\r\n\r\nPING\r\n*1\r\n$4\r\nPING\r\n");
+    assert_eq!(decoder.decode_request()?, Some(args(&[b"PING"])));
+    assert_eq!(decoder.decode_request()?, Some(args(&[b"PING"])));
+    assert_eq!(decoder.decode_request()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn decode_invalid_inline_requests() {
+    let invalid: Vec<&[u8]> = vec![
+        b"SET k \"unterminated\r\n",
+        b"SET k 'unterminated\r\n",
+        b"SET k \"closing\"quote\r\n",
+        b"SET k 'closing'quote\r\n",
+    ];
+    for input in invalid {
+        match inline(input) {
+            Err(KvsError::Protocol(_)) => {}
+            other => panic!("unexpected result for {:?}: {:?}", input, other),
+        }
+    }
+}
+
+#[test]
+fn parse_commands_from_arrays() -> Result<()> {
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("set"), bulk("k"), bulk("v")]))? {
+        ClientCommand::Set { key, value } => {
+            assert_eq!(key, b"k");
+            assert_eq!(value, b"v");
+        }
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("DEL"), bulk("k")]))? {
+        ClientCommand::Delete { keys } => assert_eq!(keys, vec![b"k".to_vec()]),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("del"), bulk("a"), bulk("b")]))? {
+        ClientCommand::Delete { keys } => assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("Exists"), bulk("k"), bulk("k")]))? {
+        ClientCommand::Exists { keys } => assert_eq!(keys, vec![b"k".to_vec(), b"k".to_vec()]),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("PING")]))? {
+        ClientCommand::PING => {}
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("multi")]))? {
+        ClientCommand::Multi => {}
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("EXEC")]))? {
+        ClientCommand::Exec => {}
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![
+        bulk("SET"),
+        bulk("k"),
+        bulk("v"),
+        bulk("nx"),
+    ]))? {
+        ClientCommand::SetIfAbsent { key, value } => {
+            assert_eq!(key, b"k");
+            assert_eq!(value, b"v");
+        }
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![
+        bulk("set"),
+        bulk("k"),
+        bulk("v"),
+        bulk("XX"),
+    ]))? {
+        ClientCommand::SetIfPresent { .. } => {}
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("SETNX"), bulk("k"), bulk("v")]))? {
+        ClientCommand::SetNx { .. } => {}
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("DECRBY"), bulk("k"), bulk("5")]))? {
+        ClientCommand::IncrBy { key, delta } => {
+            assert_eq!(key, b"k");
+            assert_eq!(delta, -5);
+        }
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![
+        bulk("SCAN"),
+        bulk("0"),
+        bulk("MATCH"),
+        bulk("user:*"),
+        bulk("count"),
+        bulk("100"),
+    ]))? {
+        ClientCommand::Scan {
+            cursor,
+            prefix,
+            count,
+            reverse,
+        } => {
+            assert_eq!(cursor, "0");
+            assert_eq!(prefix.as_deref(), Some(&b"user:"[..]));
+            assert_eq!(count, 100);
+            assert!(!reverse);
+        }
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("SCAN"), bulk("0"), bulk("REV")]))? {
+        ClientCommand::Scan { reverse, .. } => assert!(reverse),
+        other => panic!("unexpected command: {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reject_invalid_commands() {
+    let invalid = vec![
+        RespValue::Array(vec![bulk("GET")]),
+        RespValue::Array(vec![bulk("GET"), bulk("a"), bulk("b")]),
+        RespValue::Array(vec![bulk("FLUSHALL")]),
+        RespValue::Array(vec![bulk("DEL")]),
+        RespValue::Array(vec![bulk("EXISTS")]),
+        RespValue::Array(vec![bulk("SET"), bulk("k"), bulk("v"), bulk("EX")]),
+        RespValue::Array(vec![bulk("SETNX"), bulk("k")]),
+        RespValue::Array(vec![bulk("INCRBY"), bulk("k"), bulk("one")]),
+        RespValue::Array(vec![bulk("INCR"), bulk("k"), bulk("1")]),
+        RespValue::Array(vec![bulk("SCAN")]),
+        RespValue::Array(vec![bulk("SCAN"), bulk("0"), bulk("MATCH"), bulk("*:id")]),
+        RespValue::Array(vec![bulk("SCAN"), bulk("0"), bulk("COUNT"), bulk("0")]),
+        RespValue::Array(vec![]),
+        RespValue::Array(vec![RespValue::Integer(1)]),
+        bulk("GET"),
+    ];
+    for value in invalid {
+        match ClientCommand::from_resp(value) {
+            Err(KvsError::Protocol(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+// The command name echoed in an error reply must not inject RESP frames.
+#[test]
+fn sanitize_echoed_command_name() {
+    let value = RespValue::Array(vec![bulk("foo\r\n+OK")]);
+    match ClientCommand::from_resp(value) {
+        Err(KvsError::Protocol(message)) => {
+            assert_eq!(message, "unknown command 'foo  +ok'")
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let value = RespValue::Array(vec![bulk("HELLO"), bulk("3"), bulk("x\ny")]);
+    match ClientCommand::from_resp(value) {
+        Err(KvsError::Protocol(message)) => assert!(!message.contains(['\r', '\n'])),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn encode_values() {
+    assert_eq!(
+        RespValue::SimpleStrings("OK".to_owned()).to_bytes(),
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        RespValue::Error("ERR oops".to_owned()).to_bytes(),
+        b"-ERR oops\r\n"
+    );
+    assert_eq!(
+        RespValue::Error("ERR a\r\n+OK".to_owned()).to_bytes(),
+        b"-ERR a  +OK\r\n"
+    );
+    assert_eq!(RespValue::Integer(-12).to_bytes(), b":-12\r\n");
+    assert_eq!(bulk("").to_bytes(), b"$0\r\n\r\n");
+    assert_eq!(RespValue::BulkStrings(None).to_bytes(), b"$-1\r\n");
+    assert_eq!(RespValue::Array(vec![]).to_bytes(), b"*0\r\n");
+    assert_eq!(
+        RespValue::Array(vec![
+            RespValue::Array(vec![RespValue::Integer(1), bulk("a")]),
+            RespValue::BulkStrings(None),
+        ])
+        .to_bytes(),
+        b"*2\r\n*2\r\n:1\r\n$1\r\na\r\n$-1\r\n"
+    );
+}
+
+#[test]
+fn encode_resp3_values() {
+    assert_eq!(RespValue::Null.to_bytes(), b"_\r\n");
+    assert_eq!(RespValue::Boolean(true).to_bytes(), b"#t\r\n");
+    assert_eq!(RespValue::Double(1.5).to_bytes(), b",1.5\r\n");
+    assert_eq!(
+        RespValue::Double(f64::NEG_INFINITY).to_bytes(),
+        b",-inf\r\n"
+    );
+    assert_eq!(
+        RespValue::BigNumber("3492890328409238509324850943850943825024385".to_owned()).to_bytes(),
+        b"(3492890328409238509324850943850943825024385\r\n".to_vec()
+    );
+    assert_eq!(
+        RespValue::VerbatimString {
+            format: "txt".to_owned(),
+            data: b"Some string".to_vec()
+        }
+        .to_bytes(),
+        b"=15\r\ntxt:Some string\r\n"
+    );
+    assert_eq!(
+        RespValue::Map(vec![(bulk("first"), RespValue::Integer(1))]).to_bytes(),
+        b"%1\r\n$5\r\nfirst\r\n:1\r\n"
+    );
+    assert_eq!(
+        RespValue::Set(vec![RespValue::Boolean(false)]).to_bytes(),
+        b"~1\r\n#f\r\n"
+    );
+    assert_eq!(
+        RespValue::Push(vec![bulk("message")]).to_bytes(),
+        b">1\r\n$7\r\nmessage\r\n"
+    );
+}
+
+// RESP3 values are mapped onto the closest RESP2 types for older clients.
+#[test]
+fn downgrade_to_resp2() {
+    let value = RespValue::Map(vec![
+        (bulk("null"), RespValue::Null),
+        (bulk("bool"), RespValue::Boolean(true)),
+        (
+            bulk("set"),
+            RespValue::Set(vec![
+                RespValue::Double(2.5),
+                RespValue::BigNumber("7".to_owned()),
+            ]),
+        ),
+    ]);
+    assert_eq!(
+        value.into_resp2(),
+        RespValue::Array(vec![
+            bulk("null"),
+            RespValue::BulkStrings(None),
+            bulk("bool"),
+            RespValue::Integer(1),
+            bulk("set"),
+            RespValue::Array(vec![bulk("2.5"), bulk("7")]),
+        ])
+    );
+}
+
+#[test]
+fn parse_hello() -> Result<()> {
+    match ClientCommand::from_resp(RespValue::Array(vec![bulk("HELLO"), bulk("3")]))? {
+        ClientCommand::Hello { protover } => assert_eq!(protover, Some(3)),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    match ClientCommand::from_resp(RespValue::Array(vec![
+        bulk("hello"),
+        bulk("2"),
+        bulk("SETNAME"),
+        bulk("reporting"),
+    ]))? {
+        ClientCommand::Hello { protover } => assert_eq!(protover, Some(2)),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    assert!(ClientCommand::from_resp(RespValue::Array(vec![bulk("HELLO"), bulk("x")])).is_err());
+    assert!(ClientCommand::from_resp(RespValue::Array(vec![
+        bulk("HELLO"),
+        bulk("3"),
+        bulk("AUTH"),
+        bulk("user"),
+        bulk("pass"),
+    ]))
+    .is_err());
+
+    Ok(())
+}
+
+fn resp_value() -> impl Strategy<Value = RespValue> {
+    let leaf = prop_oneof![
+        "[^\r\n]*".prop_map(RespValue::SimpleStrings),
+        "[^\r\n]*".prop_map(RespValue::Error),
+        any::<i64>().prop_map(RespValue::Integer),
+        proptest::option::of(proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(RespValue::BulkStrings),
+    ];
+    let resp3_leaf = prop_oneof![
+        Just(RespValue::Null),
+        any::<bool>().prop_map(RespValue::Boolean),
+        prop_oneof![
+            any::<f64>().prop_filter("NaN never equals itself", |d| !d.is_nan()),
+            Just(f64::INFINITY),
+            Just(f64::NEG_INFINITY),
+        ]
+        .prop_map(RespValue::Double),
+        "-?[0-9]{1,40}".prop_map(RespValue::BigNumber),
+        ("[a-z]{3}", proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(format, data)| RespValue::VerbatimString { format, data }),
+    ];
+    prop_oneof![leaf, resp3_leaf].prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(RespValue::Array),
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(RespValue::Set),
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(RespValue::Push),
+            proptest::collection::vec((inner.clone(), inner), 0..4).prop_map(RespValue::Map),
+        ]
+    })
+}
+
+proptest! {
+    // Every encoded value decodes back to itself, consuming exactly its bytes.
+    #[test]
+    fn encode_decode_round_trip(value in resp_value()) {
+        let bytes = value.to_bytes();
+        let decoded = RespDecoder::new().decode(&bytes).unwrap();
+        prop_assert_eq!(decoded, Some((value, bytes.len())));
+    }
+
+    // Simple strings with CR or LF still encode to exactly one value.
+    #[test]
+    fn encode_simple_string_with_newlines(s in "[a\r\n]*") {
+        let bytes = RespValue::SimpleStrings(s.clone()).to_bytes();
+        let expected = RespValue::SimpleStrings(s.replace(['\r', '\n'], " "));
+        let decoded = RespDecoder::new().decode(&bytes).unwrap();
+        prop_assert_eq!(decoded, Some((expected, bytes.len())));
+    }
+
+    // A stream of encoded values survives being split into arbitrary chunks.
+    #[test]
+    fn decode_chunked_stream(
+        values in proptest::collection::vec(resp_value(), 1..8),
+        chunk_len in 1usize..32,
+    ) {
+        let mut bytes = Vec::new();
+        for value in &values {
+            value.encode(&mut bytes).unwrap();
+        }
+
+        let mut decoder = RespDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in bytes.chunks(chunk_len) {
+            decoder.feed(chunk);
+            while let Some(value) = decoder.decode_next().unwrap() {
+                decoded.push(value);
+            }
+        }
+        prop_assert_eq!(decoded, values);
+        prop_assert_eq!(decoder.buffered(), 0);
+    }
+}
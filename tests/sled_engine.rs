@@ -0,0 +1,185 @@
+#![cfg(feature = "sled")]
+
+use kvs::{KvsEngine, KvsError, Result, SledKvsEngine, WriteBatch};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should get previously stored value, also after reopening
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should remove a key and report a missing one
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.clone().remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// Should apply a batch as a whole and abort it on a missing key
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    engine.write(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    assert!(matches!(engine.write(batch), Err(KvsError::KeyNotFound)));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.delete("key1".to_owned());
+    batch.delete("key3".to_owned());
+    assert_eq!(engine.write(batch)?, vec![true, false, true]);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    assert!(!engine.set_if_present("key1".to_owned(), "value1".to_owned())?);
+    assert!(engine.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(engine.set_if_present("key1".to_owned(), "value2".to_owned())?);
+    assert!(!engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert!(engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    assert_eq!(engine.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(engine.incr_by("counter".to_owned(), -7)?, -2);
+    engine.set("name".to_owned(), "sled".to_owned())?;
+    assert!(matches!(
+        engine.incr_by("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn expiration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let hour = Duration::from_secs(3600);
+
+    engine.set_with_ttl("session".to_owned(), "token".to_owned(), hour)?;
+    assert!(engine.ttl("session".to_owned())?.unwrap() <= hour);
+    assert!(engine.persist("session".to_owned())?);
+    assert_eq!(engine.ttl("session".to_owned())?, None);
+    assert!(!engine.expire("missing".to_owned(), hour)?);
+
+    engine.set_with_ttl(
+        "temp".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.get("temp".to_owned())?, None);
+    assert!(matches!(
+        engine.ttl("temp".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(engine.set_if_absent("temp".to_owned(), "again".to_owned())?);
+    assert_eq!(engine.ttl("temp".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    for key in &["user:3", "order:1", "user:1", "user:2"] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+
+    let keys = |scan: kvs::Scan| -> Result<Vec<String>> {
+        scan.map(|entry| entry.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(
+        keys(engine.scan("user:".to_owned().., 2))?,
+        vec!["user:1", "user:2"]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("user:"))?,
+        vec!["user:1", "user:2", "user:3"]
+    );
+    assert_eq!(
+        keys(engine.scan_rev(.."user:3".to_owned(), 2))?,
+        vec!["user:2", "user:1"]
+    );
+
+    Ok(())
+}
+
+// Keys and values that are not UTF-8 should be stored as is
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    engine.set_bytes(vec![0x00, 0xff], vec![0xfe, 0x80])?;
+    engine.set_bytes(b"key1".to_vec(), vec![0xfe, 0x80])?;
+    assert_eq!(engine.get_bytes(vec![0x00, 0xff])?, Some(vec![0xfe, 0x80]));
+    assert!(matches!(
+        engine.get("key1".to_owned()),
+        Err(KvsError::EncodeError(_))
+    ));
+    let keys = engine
+        .scan_bytes(vec![0x00]..vec![0x01], usize::MAX)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![vec![0x00, 0xff]]);
+
+    Ok(())
+}